@@ -12,10 +12,33 @@ use proc_macro2::Span;
 use std::collections::HashSet;
 use syn::*;
 
+fn impl_fingerprint(ast: &syn::DeriveInput, field_ty: &HashSet<Type>, body: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    let name = &ast.ident;
+
+    let mut impl_generics: Generics = ast.generics.clone();
+    impl_generics.where_clause = Some(parse_quote!(where Self: 'static, #(#field_ty: Fingerprint,)*));
+
+    let (_, type_generics, _) = ast.generics.split_for_impl();
+    let (impl_generics, _, where_clause) = impl_generics.split_for_impl();
+
+    quote! {
+        impl #impl_generics Fingerprint for #name #type_generics #where_clause {
+            fn fingerprint() -> u64 {
+                cache_fingerprint::<Self, _>(|| {
+                    let mut hasher = Fingerprinter::new();
+                    #body
+                    hasher.finish()
+                })
+            }
+        }
+    }
+}
+
 fn impl_reflect_struct(ast: &syn::DeriveInput) -> TokenStream {
     let name = &ast.ident;
     let mut field_id = Vec::<Member>::new();
     let mut field_ty = HashSet::new();
+    let mut field_types = Vec::<Type>::new();
 
     match &ast.data {
         &Data::Struct(ref data) => {
@@ -24,12 +47,14 @@ fn impl_reflect_struct(ast: &syn::DeriveInput) -> TokenStream {
                     for i in fields.named.iter() {
                         field_id.push(Member::Named(i.ident.clone().unwrap()));
                         field_ty.insert(i.ty.clone());
+                        field_types.push(i.ty.clone());
                     }
                 },
                 &Fields::Unnamed(ref fields) => {
                     for (i, f) in fields.unnamed.iter().enumerate() {
                         field_id.push(Member::Unnamed(Index::from(i)));
                         field_ty.insert(f.ty.clone());
+                        field_types.push(f.ty.clone());
                     }
                 },
                 &Fields::Unit => {
@@ -40,6 +65,18 @@ fn impl_reflect_struct(ast: &syn::DeriveInput) -> TokenStream {
         _ => unreachable!(),
     };
 
+    let field_str2: Vec<Member> = field_id.clone();
+    let field_count = field_id.len() as u32;
+
+    let fingerprint = impl_fingerprint(ast, &field_ty, quote! {
+        hasher.write_str("struct");
+        hasher.write_u32(#field_count);
+        #(
+            hasher.write_str(stringify!(#field_str2));
+            hasher.write_fingerprint(<#field_types as Fingerprint>::fingerprint());
+        )*
+    });
+
     let mut impl_generics: Generics = ast.generics.clone();
     impl_generics.params.push(parse_quote!(V: Visitor));
     impl_generics.where_clause = Some(parse_quote!(where #(#field_ty: Reflect<V>,)*));
@@ -56,6 +93,8 @@ fn impl_reflect_struct(ast: &syn::DeriveInput) -> TokenStream {
                 Ok(())
             }
         }        
+
+        #fingerprint
     };
 
     tokens.into()
@@ -65,12 +104,13 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
     let name = &ast.ident;
     let mut field_ty = HashSet::new();
 
-    let (variants, indices, reflect_variants, construct_variants) = match &ast.data {
+    let (variants, indices, reflect_variants, construct_variants, fingerprint_variants) = match &ast.data {
         &Data::Enum(ref data) => {
             let mut variants = Vec::new();
             let mut indices = Vec::new();
             let mut reflect_variants = Vec::new();
             let mut construct_variants = Vec::new();
+            let mut fingerprint_variants = Vec::new();
 
             let mut index = 0;
             for v in data.variants.iter() {
@@ -80,6 +120,11 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
 
                 match &v.fields {
                     &Fields::Unit => {
+                        fingerprint_variants.push(quote!{
+                            hasher.write_u32(#index as u32);
+                            hasher.write_str(stringify!(#ident));
+                            hasher.write_u32(0);
+                        });
                         variants.push(quote!{#name::#ident});
                         reflect_variants.push(quote!{#name::#ident => ()});
                         construct_variants.push(quote!{#name::#ident});
@@ -100,8 +145,21 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
                         let names3 = names.clone();
                         let names4 = names.clone();
                         let names5 = names.clone();
+                        let names6 = names.clone();
+                        let types2 = types.clone();
+                        let count = names.len() as u32;
                         let fields = fields.named.iter();
 
+                        fingerprint_variants.push(quote!{
+                            hasher.write_u32(#index as u32);
+                            hasher.write_str(stringify!(#ident));
+                            hasher.write_u32(#count);
+                            #(
+                                hasher.write_str(stringify!(#names6));
+                                hasher.write_fingerprint(<#types2 as Fingerprint>::fingerprint());
+                            )*
+                        });
+
                         variants.push(quote!{#name::#ident { .. }});
 
                         reflect_variants.push(quote!{
@@ -129,6 +187,7 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
                             .map(|f| f.ty.clone())
                             .collect();
                         let types2 = types.clone();
+                        let types3 = types.clone();
                         let names: Vec<Ident> = fields.unnamed.iter()
                             .enumerate()
                             .map(|(i, _)| Ident::new(&format!("v{}", i), Span::call_site()))
@@ -142,6 +201,14 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
 
                         variants.push(quote!{#name::#ident(#(#underscores,)*)});
 
+                        let count = types3.len() as u32;
+                        fingerprint_variants.push(quote!{
+                            hasher.write_u32(#index as u32);
+                            hasher.write_str(stringify!(#ident));
+                            hasher.write_u32(#count);
+                            #(hasher.write_fingerprint(<#types3 as Fingerprint>::fingerprint());)*
+                        });
+
                         reflect_variants.push(quote!{
                             &mut #name::#ident(#(ref mut #names),*) => {
                                 #(#names2.reflect(visitor)?;)*
@@ -162,11 +229,18 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
                 index += 1;
             }
 
-            (variants, indices, reflect_variants, construct_variants)
+            (variants, indices, reflect_variants, construct_variants, fingerprint_variants)
         },
         _ => unreachable!(),
     };
 
+    let variant_count = indices.len() as u32;
+    let fingerprint = impl_fingerprint(ast, &field_ty, quote! {
+        hasher.write_str("enum");
+        hasher.write_u32(#variant_count);
+        #(#fingerprint_variants)*
+    });
+
     field_ty.insert(parse_quote!(u8));
 
     let decode_indices: Vec<u8> = indices.clone();
@@ -206,6 +280,8 @@ fn impl_reflect_enum(ast: &syn::DeriveInput) -> TokenStream {
                 Ok(())
             }
         }
+
+        #fingerprint
     };
 
     tokens.into()
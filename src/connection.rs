@@ -1,29 +1,420 @@
 use super::*;
 use std::cmp::Ordering;
-use std::sync::mpsc::{channel, Receiver};
 use std::sync::atomic::{AtomicBool,Ordering as AtomicOrdering};
+use std::collections::{HashMap, VecDeque};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::hash::{Hash, Hasher};
+use std::mem;
+use byteorder::{ByteOrder, BigEndian};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::TINFLStatus;
+use miniz_oxide::inflate::core::{decompress as inflate, inflate_flags, DecompressorOxide};
+use encryption::{Cipher, OVERHEAD};
 
 pub const PACKET_MAGIC: u32 = 0x12345678;
 
+/// Flag for `Packet`s that are handled by the `Connection` itself. These are never returned by `recv`.
+const FLAG_CONTROL: u8 = 0x01;
+
+/// Flag for `Packet`s that have a CRC32 of `node` and the data appended to their data.
+const FLAG_CHECKSUM: u8 = 0x02;
+
+/// Flag for `Packet`s that have deflate compressed data, preceded by the uncompressed length.
+const FLAG_COMPRESSED: u8 = 0x04;
+
+/// Flag for `Packet`s that have encrypted data.
+const FLAG_ENCRYPTED: u8 = 0x08;
+
+/// Flag for `Packet`s that were sent with `Connection::send_unreliable`. 
+/// Their data starts with a sequence number, so stale `Packet`s can be dropped.
+const FLAG_UNRELIABLE: u8 = 0x10;
+
+/// Flag for `Packet`s that carry a fragment of a larger message. 
+/// Their data starts with a `FRAGMENT_HEADER` that identifies the message and the fragment.
+const FLAG_FRAGMENT: u8 = 0x20;
+
+/// Size of the message id, fragment index and fragment count that precede a fragment.
+const FRAGMENT_HEADER: usize = 8;
+
 #[derive(Reflect, Default)]
 pub struct Packet {
     pub node: u32,
     magic: u32,
+    flags: u8,
     pub data: Vec<u8>,
 }
 
+impl Packet {
+    /// Initialize a new `Packet`. 
+    /// A `Transport` only needs this when it doesn't use `Reflect` to read `Packet`s.
+    pub fn new(node: u32, flags: u8, data: Vec<u8>) -> Self {
+        Packet { node, magic: PACKET_MAGIC, flags, data }
+    }
+
+    /// The flags of the `Packet`, which a `Transport` needs to send along with the data.
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    /// Returns true if the `Packet` was sent with `Connection::send_unreliable`.
+    /// A `Transport` may deliver these late, or not at all.
+    pub fn is_unreliable(&self) -> bool {
+        self.flags & FLAG_UNRELIABLE != 0
+    }
+
+    /// Returns an error if the `Packet` doesn't start with `PACKET_MAGIC`.
+    /// A `Transport` that uses `Reflect` to read `Packet`s should check this.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.magic == PACKET_MAGIC {
+            Ok(())
+        } else {
+            Err(Error::Custom("Corrupt Packet".into()))
+        }
+    }
+}
+
+/// The reason a `Connection` was closed on purpose using `Connection::close`.
+/// The reason is sent to the remote end, where it is reported by `Connection::status`.
+#[derive(Reflect, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DisconnectReason {
+    /// The client was removed by the server.
+    Kicked,
+    /// The remote end is shutting down.
+    #[default]
+    Shutdown,
+    /// The remote end did not hear from us in time.
+    Timeout,
+    /// The remote end received something it could not process.
+    ProtocolError,
+    /// The server was unable to accept the connection.
+    Refused,
+}
+
+/// The state of a `Connection`, see `Connection::state`.
+#[derive(Clone, Debug)]
+pub enum ConnectionState {
+    /// Nothing has been received from the remote end yet.
+    Connecting,
+    /// The remote end has been heard from.
+    Connected,
+    /// The `Connection` was closed on purpose, but the `Transport` is still sending 
+    ///  what was queued before, like the close frame.
+    Closing,
+    /// The `Connection` has ended, nothing is sent or received anymore.
+    Closed(CloseReason),
+}
+
+/// Why a `Connection` ended.
+#[derive(Clone, Debug)]
+pub enum CloseReason {
+    /// Closed by this end, with `Connection::close` or because the remote end timed out.
+    Local(DisconnectReason),
+    /// Closed by the remote end, which sent a close frame.
+    Remote(DisconnectReason),
+    /// The `Connection` failed, for example because the `Transport` returned an error or 
+    ///  something was received that could not be processed.
+    Error(Error),
+}
+
+impl CloseReason {
+    /// The error reported by `Connection::status` for this reason.
+    fn error(&self) -> Error {
+        match *self {
+            CloseReason::Local(reason) | CloseReason::Remote(reason) => Error::Disconnected(reason),
+            CloseReason::Error(ref e) => e.clone(),
+        }
+    }
+}
+
+/// The current `ConnectionState` and every state that came before it.
+struct State {
+    current: ConnectionState,
+    /// Why the `Connection` will be closed once it's done `Closing`.
+    closing: Option<CloseReason>,
+    history: Vec<(Instant, ConnectionState)>,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            current: ConnectionState::Connecting,
+            closing: None,
+            history: vec![(Instant::now(), ConnectionState::Connecting)],
+        }
+    }
+
+    fn set(&mut self, state: ConnectionState) {
+        self.history.push((Instant::now(), state.clone()));
+        self.current = state;
+    }
+
+    fn connected(&mut self) {
+        if let ConnectionState::Connecting = self.current {
+            self.set(ConnectionState::Connected);
+        }
+    }
+
+    /// Records why the `Connection` ended. Only the first reason is kept, since whatever 
+    ///  happens after it is usually caused by it. 
+    /// The `Connection` is `Closing` until nothing is `queued` anymore.
+    fn close(&mut self, reason: CloseReason, queued: usize) {
+        match self.current {
+            ConnectionState::Closing | ConnectionState::Closed(_) => (),
+            _ if queued > 0 => {
+                self.closing = Some(reason);
+                self.set(ConnectionState::Closing);
+            },
+            _ => self.set(ConnectionState::Closed(reason)),
+        }
+    }
+
+    /// Moves from `Closing` to `Closed` once nothing is `queued` anymore.
+    fn drain(&mut self, queued: usize) {
+        if let ConnectionState::Closing = self.current {
+            if queued == 0 {
+                let reason = self.closing.take().unwrap();
+                self.set(ConnectionState::Closed(reason));
+            }
+        }
+    }
+
+    fn error(&self) -> Error {
+        match self.current {
+            ConnectionState::Closed(ref reason) => reason.error(),
+            _ => match self.closing {
+                Some(ref reason) => reason.error(),
+                None => Error::Custom("Connection Closed".into()),
+            },
+        }
+    }
+}
+
+/// Messages sent in control `Packet`s.
+#[derive(Reflect)]
+enum Control {
+    Close(DisconnectReason),
+    Heartbeat,
+    /// Tells the remote end whether we accept compressed `Packet`s.
+    Compression(bool),
+    /// Asks the remote end to reply with a `Pong`, carrying our time in microseconds.
+    Ping(u64),
+    /// Reply to a `Ping`, carrying the time of the `Ping` and the current unix time 
+    ///  of the remote end, both in microseconds.
+    Pong(u64, u64),
+    /// The salt this end contributes to the session keys, see `Config::encryption`. 
+    /// This is the only `Packet` that is sent unencrypted while encryption is enabled.
+    Salt(Vec<u8>),
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control::Close(DisconnectReason::default())
+    }
+}
+
+/// Settings for a `Connection`, see `Connection::configure`.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// A heartbeat is sent when nothing else has been sent for this long, 
+    ///  so the remote end knows the `Connection` is still alive. 
+    /// `None` disables heartbeats, which is the default.
+    pub heartbeat: Option<Duration>,
+    /// The `Connection` is closed with `DisconnectReason::Timeout` when nothing has been received 
+    ///  for this long. `None` disables the timeout, which is the default. 
+    /// Set it together with `Config::heartbeat` on both ends, or idle connections time out.
+    pub timeout: Option<Duration>,
+    /// Append a CRC32 checksum to every `Packet` that is sent. Received `Packet`s that carry a 
+    ///  checksum are always verified, a mismatch closes the `Connection` with `Error::ChecksumMismatch`.
+    pub checksum: bool,
+    /// Limits applied to everything received on the `Connection`. 
+    /// `Limits::max_packet_size` is checked before the data of a `Packet` is read.
+    pub limits: Limits,
+    /// Compress the data of outgoing `Packet`s. Compression is negotiated: it's only used once the
+    ///  remote end has announced that it has compression enabled as well. `None` disables compression.
+    /// A `Server` that compresses waits for the announcement before it sends the root `Node`, 
+    ///  see `Client::with_config`.
+    pub compression: Option<Compression>,
+    /// Encrypt and authenticate the data of all `Packet`s using this pre-shared key. 
+    /// Both ends must use the same key, unencrypted `Packet`s are refused while it is set.
+    /// The node id and length of a `Packet` are authenticated, but not hidden. 
+    /// When the key is set, both ends send a random salt from which keys for just this `Connection` 
+    ///  are derived, so recorded `Packet`s can't be replayed into another `Connection`. 
+    /// Until the salt of the remote end has arrived, everything that is sent is deferred. 
+    /// The key should be set before anything is sent and not be changed afterwards.
+    pub encryption: Option<Key>,
+    /// Split messages into fragments so no `Packet` carries more than this many bytes of data.
+    /// Fragments are reassembled by the remote end, `Limits::max_packet_size` applies to the 
+    ///  reassembled message. Transports that need smaller `Packet`s, like `ReliableUdpTransport`, 
+    ///  lower this further on their own. `None` leaves it up to the `Transport`.
+    /// The fragments of a message are sent back to back and are not interleaved with other messages, 
+    ///  since reliable messages have to arrive in the order they were sent. On a stream `Transport` 
+    ///  a large message therefore still delays what is sent after it, use a separate `Connection` 
+    ///  for bulk data that should not hold up other traffic.
+    pub fragment_size: Option<usize>,
+    /// What to do when the remote end doesn't keep up with the data that is sent to it.
+    pub send_queue: SendQueue,
+    /// A ping is sent this often to measure the round-trip time and the clock of the remote end, 
+    ///  see `Connection::rtt`. `None` disables pings, which is the default. 
+    /// The remote end replies to pings either way.
+    pub ping: Option<Duration>,
+    /// Limits the outgoing data to this many bytes per second, in bursts of up to one second 
+    ///  worth of data. Messages beyond the budget are deferred until the `Connection` is polled 
+    ///  with budget to spare, unreliable messages are dropped instead. 
+    /// Control packets like heartbeats are never deferred, but they do count against the budget. 
+    /// Deferred messages count towards `Config::send_queue`. `None` disables the limit.
+    pub bandwidth: Option<usize>,
+}
+
+/// Settings for the compression of `Packet` data, see `Config::compression`.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    /// Data smaller than this many bytes is sent uncompressed.
+    pub threshold: usize,
+    /// The deflate level, from 0 (fastest) to 10 (smallest).
+    pub level: u8,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            threshold: 256,
+            level: 6,
+        }
+    }
+}
+
+/// Settings for the outgoing data of a `Connection`, see `Config::send_queue`.
+/// Data that is sent is queued by the `Transport` until it can be handed to the network. 
+#[derive(Clone, Debug)]
+pub struct SendQueue {
+    /// The `policy` kicks in when more than this many bytes are queued.
+    pub high_water_mark: usize,
+    /// Defaults to `QueuePolicy::DropClient`, so a `Server` is never held up by a client that 
+    ///  stopped reading.
+    pub policy: QueuePolicy,
+    /// How long `QueuePolicy::Block` waits for the queue to drain before giving up.
+    pub block_timeout: Duration,
+}
+
+impl Default for SendQueue {
+    fn default() -> Self {
+        Self {
+            high_water_mark: 1 << 20,
+            policy: QueuePolicy::DropClient,
+            block_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What happens to data that is sent while the send queue is above it's high-water mark.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QueuePolicy {
+    /// The `Connection` is closed with `Error::LimitExceeded`.
+    DropClient,
+    /// Data sent with `Connection::send_unreliable` is dropped, other data is queued anyway.
+    DropUnreliable,
+    /// Sending blocks until the queue has drained below the high-water mark. 
+    /// If that takes longer than `SendQueue::block_timeout`, the `Connection` is closed with 
+    ///  `Error::LimitExceeded` like with `DropClient`. 
+    /// Blocking in `Server::update` holds up all other clients, so this is best used for a `Client`.
+    Block,
+}
+
+/// Traffic statistics of a `Connection`, see `Connection::stats`.
+/// Everything is counted per `Packet` as it's handed to or received from the `Transport`, 
+///  so heartbeats, fragments and the overhead of compression or encryption are included. 
+/// The framing a `Transport` adds is not.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Bytes waiting in the send queue, see `Config::send_queue`.
+    pub queued: usize,
+    /// When the last `Packet` was sent, or `None` if nothing was sent yet.
+    pub last_sent: Option<Instant>,
+    /// When the last `Packet` was received, or `None` if nothing was received yet.
+    pub last_received: Option<Instant>,
+}
+
+impl Stats {
+    /// Adds the statistics of another `Connection` to these. Timestamps keep the latest activity.
+    pub fn merge(&mut self, other: &Stats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.packets_sent += other.packets_sent;
+        self.packets_received += other.packets_received;
+        self.queued += other.queued;
+        self.last_sent = self.last_sent.max(other.last_sent);
+        self.last_received = self.last_received.max(other.last_received);
+    }
+}
+
+/// Round-trip time and clock offset, smoothed over all pongs received so far.
+struct Latency {
+    rtt: Duration,
+    jitter: Duration,
+    /// Microseconds to add to our unix time to get the unix time of the remote end.
+    clock_offset: i64,
+}
+
+/// The fragments of a message that have been received so far.
+struct Fragments {
+    node: u32,
+    flags: u8,
+    parts: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+}
+
+impl Fragments {
+    /// Bytes held by the fragments, including the list of parts.
+    fn buffered(&self) -> usize {
+        self.size + self.parts.len() * mem::size_of::<Option<Vec<u8>>>()
+    }
+}
+
 struct Conn {
-    w: Serializer<Box<Write>>,
-    r: Receiver<Packet>,
+    transport: Box<Transport>,
+    packets: VecDeque<Packet>,
+    config: Config,
+    /// Set when the remote end announced that it accepts compressed `Packet`s.
+    remote_compression: bool,
+    cipher: Option<Cipher>,
+    /// Sequence number of the last unreliable `Packet` that was sent.
+    unreliable_sent: u32,
+    /// Sequence number of the last unreliable `Packet` that was received, per node.
+    unreliable_received: HashMap<u32, u32>,
+    /// Id of the last message that was split into fragments.
+    fragmented_sent: u32,
+    /// Messages that have not received all of their fragments yet, by message id.
+    fragmented_received: HashMap<u32, Fragments>,
+    stats: Stats,
+    state: Arc<Mutex<State>>,
+    /// Messages that are waiting for bandwidth, see `Config::bandwidth`.
+    deferred: VecDeque<(u32, u8, Vec<u8>)>,
+    deferred_bytes: usize,
+    /// Bytes that can still be sent within `Config::bandwidth`, may be negative after a large message.
+    budget: f64,
+    budget_updated: Instant,
+    /// Start of the clock that is sent in pings.
+    epoch: Instant,
+    last_ping: Option<Instant>,
+    latency: Option<Latency>,
+    last_sent: Instant,
+    last_recv: Instant,
 }
 
-/// A `Connection`. This struct wraps around a `Write` and `Read` implementation that should be
-///  mapped to a tcp socket.
+/// A `Connection`. This struct wraps around a `Transport`, which is usually a `Write` and `Read`
+///  implementation that should be mapped to a tcp socket, or an in-process channel when created 
+///  with `Connection::pair`. 
+/// A `Connection` created with `Connection::new` reads and writes from dedicated threads, 
+///  while one created with `Connection::polled` only does I/O when it is polled.
 pub struct Connection {
     inner: Arc<Mutex<Conn>>,
-    err: Arc<Mutex<Option<Error>>>,
+    state: Arc<Mutex<State>>,
     alive: Arc<AtomicBool>,
     id: usize,
 }
@@ -58,7 +449,7 @@ impl Clone for Connection {
     fn clone(&self) -> Self {
         Connection {
             inner: self.inner.clone(),
-            err: self.err.clone(),
+            state: self.state.clone(),
             alive: self.alive.clone(),
             id: self.id,
         }
@@ -69,87 +460,1086 @@ impl Connection {
     /// Initialize a new `Connection`. 
     /// When used for a `Client<T>` the `id` parameter can be anything, it is only used in `Server`.
     /// The id is what determines ordering and equality for `Connection`.
-    pub fn new<W: 'static +  Write, R: 'static +  Read + Send>(w: W, r: R, id: usize) -> Self {
-        let (sender, receiver) = channel();
+    /// Every `Connection` created this way uses two threads, one that blocks reading from `r` and 
+    ///  one that writes to `w`, so sending never blocks. For many connections, 
+    ///  consider `Connection::polled`, which does all of it's work on the thread that polls it.
+    /// When `w` is a `TcpStream` or a `UnixStream`, it is shut down once the `Connection` is closed 
+    ///  and everything has been sent, which stops the reader thread. 
+    pub fn new<W: 'static + Write + Send, R: 'static + Read + Send>(w: W, r: R, id: usize) -> Self {
+        Self::from_transport(StreamTransport::new(w, r), id)
+    }
 
-        let inner = Arc::new(Mutex::new(Conn { w: Serializer::new(Box::new(w)), r: receiver }));
-        let alive = Arc::new(AtomicBool::new(true));
-        let err = Arc::new(Mutex::new(None));
+    /// Initialize a new `Connection` that does not spawn any threads. 
+    /// The supplied `Write` and `Read` implementations, like the halves of a `TcpStream`, 
+    ///  are switched to non-blocking mode. Fails if that is not possible.
+    /// Reading and writing only happens when the `Connection` is polled, which `Server::update`
+    ///  and `Client::update` do for all of their connections.
+    /// Like `Connection::new`, the id is what determines ordering and equality for `Connection`.
+    pub fn polled<W, R>(w: W, r: R, id: usize) -> Result<Self, Error> where
+        W: 'static + Write + NonBlocking,
+        R: 'static + Read + NonBlocking,
+    {
+        Ok(Self::from_transport(PolledTransport::new(w, r)?, id))
+    }
 
-        let result = Connection{
-            inner: inner.clone(),
-            alive: alive.clone(),
-            err: err.clone(),
-            id
-        };
+    /// Initialize two `Connection`s that are connected to each other through in-process channels.
+    /// A `Packet` sent on one end is available on the other end as soon as `send` returns,
+    ///  which makes the pair suitable for testing a `Server` and `Client` without sockets.
+    /// Both ends are assigned the same `id`.
+    pub fn pair(id: usize) -> (Self, Self) {
+        let (a, b) = ChannelTransport::pair();
 
-        thread::spawn(move || {
-            let mut de = Deserializer::new(r);
-            while alive.load(AtomicOrdering::Relaxed) {
-                let mut packet = Packet::default();
-                let result = packet.reflect(&mut de);
-                if result.is_err() {
-                    *err.lock().unwrap() = Some(result.err().unwrap());
-                    break;
-                }
-                if packet.magic != PACKET_MAGIC {
-                    *err.lock().unwrap() = Some(Error::Custom("Corrupt Packet".into()));
-                    break;
-                }
-                if sender.send(packet).is_err() {
-                    *err.lock().unwrap() = Some(Error::Custom("Channel Error".into()));
-                    break;
-                }
-            }
+        (Self::from_transport(a, id), Self::from_transport(b, id))
+    }
 
-            alive.swap(false, AtomicOrdering::Relaxed);
-        });  
+    /// Initialize a new `Connection` on top of a custom `Transport`.
+    /// Like `Connection::new`, the id is what determines ordering and equality for `Connection`.
+    pub fn from_transport<T: 'static + Transport>(transport: T, id: usize) -> Self {
+        let state = Arc::new(Mutex::new(State::new()));
 
-        result     
+        Connection {
+            inner: Arc::new(Mutex::new(Conn::new(Box::new(transport), state.clone()))),
+            alive: Arc::new(AtomicBool::new(true)),
+            state,
+            id
+        }
     }
 
-    /// When one of the wrapped `Write` or `Read` implementations return an error
-    ///  the `Connection` is flagged as dead internally. This function can be used to check if the
+    /// When the wrapped `Transport` returns an error the `Connection` is flagged as dead internally. This function can be used to check if the
     /// `Connection` is still alive. 
     /// This function will return `Ok(())` if the `Connection` is alive, or an `Err(_)` if it isn't.
+    /// The error describes why the `Connection` ended, see `Connection::state` for the details.
     pub fn status(&self) -> Result<(), Error> {
         if self.alive.load(AtomicOrdering::Relaxed) {
             Ok(())
         } else {
-            Err(self.err.lock().unwrap().take().unwrap())
+            Err(self.state.lock().unwrap().error())
+        }
+    }
+
+    /// Returns the id the `Connection` was created with.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns why the `Connection` ended, or why it will once it's done `Closing`. 
+    /// Returns `None` while the `Connection` is alive.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        let state = self.state.lock().unwrap();
+        match state.current {
+            ConnectionState::Closed(ref reason) => Some(reason.clone()),
+            _ => state.closing.clone(),
+        }
+    }
+
+    /// Returns the current state of the `Connection`.
+    pub fn state(&self) -> ConnectionState {
+        // don't wait for a `recv_blocking` on another thread, the state is updated on the next call
+        if let Ok(conn) = self.inner.try_lock() {
+            self.state.lock().unwrap().drain(conn.queued());
+        }
+        self.state.lock().unwrap().current.clone()
+    }
+
+    /// Returns every state the `Connection` has been in, along with when it got there. 
+    /// The last entry is the current state, as of the last call to `Connection::state`.
+    pub fn history(&self) -> Vec<(Instant, ConnectionState)> {
+        self.state.lock().unwrap().history.clone()
+    }
+
+    /// Replaces the `Config` of this `Connection`. 
+    /// Both ends of a `Connection` should use compatible settings: 
+    ///  the heartbeat interval should be well below the timeout of the remote end.
+    /// If compression is switched on or off the remote end is notified.
+    pub fn configure(&self, config: Config) {
+        let result = self.inner.lock().unwrap().configure(config);
+        if result.is_err() {
+            self.fail(result.err().unwrap());
+        }
+    }
+
+    /// Returns a copy of the current `Config` of this `Connection`.
+    pub fn config(&self) -> Config {
+        self.inner.lock().unwrap().config.clone()
+    }
+
+    /// Returns true once the remote end has announced that it has compression enabled, 
+    ///  see `Config::compression`.
+    pub fn remote_compression(&self) -> bool {
+        self.inner.lock().unwrap().remote_compression
+    }
+
+    /// Returns the smoothed round-trip time, measured using pings, see `Config::ping`.
+    /// Returns `None` until the first reply to a ping has been received.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.inner.lock().unwrap().latency.as_ref().map(|l| l.rtt)
+    }
+
+    /// Returns how much the round-trip time varies, like `Connection::rtt`.
+    pub fn jitter(&self) -> Option<Duration> {
+        self.inner.lock().unwrap().latency.as_ref().map(|l| l.jitter)
+    }
+
+    /// Returns an estimate of the current time on the clock of the remote end, which is 
+    ///  synchronised using pings like `Connection::rtt`. A `Client` can use this to relate 
+    ///  timestamps sent by the `Server` to it's own clock.
+    pub fn remote_time(&self) -> Option<SystemTime> {
+        let conn = self.inner.lock().unwrap();
+        let offset = conn.latency.as_ref()?.clock_offset;
+        let time = unix_micros() as i64 + offset;
+        Some(UNIX_EPOCH + Duration::from_micros(time.max(0) as u64))
+    }
+
+    /// Returns the traffic statistics of this `Connection`. 
+    /// Received `Packet`s are counted when the `Connection` is polled.
+    pub fn stats(&self) -> Stats {
+        let conn = self.inner.lock().unwrap();
+        let mut stats = conn.stats.clone();
+        stats.queued = conn.queued();
+        stats
+    }
+
+    /// Closes the `Connection` on purpose. A close frame containing `reason` is sent first,
+    ///  after which the remote end will report `Error::Disconnected(reason)` from `status`.
+    /// Locally, `status` will report the same error and nothing is sent or received anymore. 
+    /// The `Transport` is closed as well, a reader thread stops as soon as it's current read returns.
+    pub fn close(&self, reason: DisconnectReason) {
+        if self.alive.load(AtomicOrdering::Relaxed) {
+            self.inner.lock().unwrap().close(reason);
+            self.fail(Error::Disconnected(reason));
         }
     }
 
     /// Send a message destined for the `Node` with id `node` over the `Connection`.
-    pub fn send(&self, mut node: u32, data: &[u8]) {
-        let mut conn = self.inner.lock().unwrap();
+    /// The message is queued by the `Transport`, `Config::send_queue` decides what happens when 
+    ///  the remote end doesn't keep up. Messages beyond `Config::bandwidth` are deferred to a later `poll`.
+    pub fn send(&self, node: u32, data: &[u8]) {
+        if !self.alive.load(AtomicOrdering::Relaxed) {
+            return;
+        }
 
-        let mut x = move || -> Result<(), Error> {
-            node.reflect(&mut conn.w)?;
-            PACKET_MAGIC.reflect(&mut conn.w)?;
-            (data.len() as u32).reflect(&mut conn.w)?;
-            conn.w.writer.write_all(data)?;
+        let result = self.inner.lock().unwrap().write(node, 0, data);
+        if result.is_err() {
+            self.fail(result.err().unwrap());
+        }
+    }
 
-            Ok(())
-        };
+    /// Send a message destined for the `Node` with id `node` without delivery guarantees.
+    /// If the `Transport` supports it, the message is sent on an unreliable channel, like a UDP 
+    ///  datagram. Otherwise it is sent like any other message. 
+    /// The message is dropped by the remote end if a newer unreliable message for the same
+    ///  `Node` was received before it.
+    pub fn send_unreliable(&self, node: u32, data: &[u8]) {
+        if !self.alive.load(AtomicOrdering::Relaxed) {
+            return;
+        }
 
-        let result = x();
+        let result = self.inner.lock().unwrap().write_unreliable(node, data);
         if result.is_err() {
-            *self.err.lock().unwrap() = result.err();
-            self.alive.swap(false, AtomicOrdering::Relaxed);
+            self.fail(result.err().unwrap());
+        }
+    }
+
+    /// Performs pending work on the `Connection`: heartbeats are sent, timeouts are detected and
+    ///  received `Packet`s are queued so they can be retrieved with `recv`. 
+    /// This is also where the `Transport` is polled, which for a `Connection` created with 
+    ///  `Connection::polled` means buffered data is written and available data is read.
+    /// `Server::update` and `Client::update` poll all of their connections.
+    /// While the `Connection` is `Closing`, the `Transport` is still polled so it can send 
+    ///  what was queued before the close, nothing is received anymore.
+    pub fn poll(&self) {
+        if self.alive.load(AtomicOrdering::Relaxed) {
+            let result = self.inner.lock().unwrap().poll();
+            if result.is_err() {
+                self.fail(result.err().unwrap());
+            }
+        } else {
+            self.drain();
         }
     }
 
     /// Returns `Some(Packet)` if there is one available now, otherwise returns `None`.
     pub fn recv(&self) -> Option<Packet> {
-        let conn = self.inner.lock().unwrap();
-        conn.r.try_recv().ok()
+        self.poll();
+        self.inner.lock().unwrap().packets.pop_front()
     }
 
     /// Blocks until a `Packet` is available and then returns `Some(Packet)`. 
     /// If the `Connection` dies while blocking, this function will return `None`.
     pub fn recv_blocking(&self) -> Option<Packet> {
-        let conn = self.inner.lock().unwrap();
-        conn.r.recv().ok()
+        let mut conn = self.inner.lock().unwrap();
+
+        loop {
+            let packet = conn.packets.pop_front();
+            if packet.is_some() || !self.alive.load(AtomicOrdering::Relaxed) {
+                return packet;
+            }
+
+            let result = conn.poll().and_then(|_| if conn.packets.is_empty() { 
+                conn.wait()
+            } else { 
+                Ok(()) 
+            });
+            if result.is_err() {
+                self.fail(result.err().unwrap());
+            }
+        }
+    }
+
+    /// Polls the `Transport` of a `Closing` connection, which is `Closed` once it has sent 
+    ///  everything or failed.
+    fn drain(&self) {
+        if !matches!(self.state.lock().unwrap().current, ConnectionState::Closing) {
+            return;
+        }
+
+        let mut conn = self.inner.lock().unwrap();
+        let queued = match conn.transport.poll(&mut Vec::new()) {
+            Ok(()) => conn.queued(),
+            Err(_) => 0,
+        };
+        self.state.lock().unwrap().drain(queued);
+    }
+
+    /// Flags the `Connection` as dead. Only the first error is kept, since errors that follow
+    ///  are usually caused by it. Errors that follow a close are not kept at all.
+    fn fail(&self, error: Error) {
+        self.state.lock().unwrap().close(CloseReason::Error(error), 0);
+        self.alive.swap(false, AtomicOrdering::Relaxed);
+    }
+}
+
+impl Conn {
+    fn new(transport: Box<Transport>, state: Arc<Mutex<State>>) -> Self {
+        Self {
+            transport,
+            packets: VecDeque::new(),
+            config: Config::default(),
+            remote_compression: false,
+            cipher: None,
+            unreliable_sent: 0,
+            unreliable_received: HashMap::new(),
+            fragmented_sent: 0,
+            fragmented_received: HashMap::new(),
+            stats: Stats::default(),
+            state,
+            deferred: VecDeque::new(),
+            deferred_bytes: 0,
+            budget: 0.0,
+            budget_updated: Instant::now(),
+            epoch: Instant::now(),
+            last_ping: None,
+            latency: None,
+            last_sent: Instant::now(),
+            last_recv: Instant::now(),
+        }
+    }
+
+    fn configure(&mut self, config: Config) -> Result<(), Error> {
+        let compression = config.compression.is_some();
+        let changed = compression != self.config.compression.is_some();
+
+        let key_changed = config.encryption.as_ref() != self.cipher.as_ref().map(|c| c.key());
+        if key_changed {
+            self.cipher = match config.encryption {
+                Some(ref key) => Some(Cipher::new(key.clone())?),
+                None => None,
+            };
+        }
+
+        if config.bandwidth != self.config.bandwidth {
+            self.budget = config.bandwidth.unwrap_or(0) as f64;
+            self.budget_updated = Instant::now();
+        }
+
+        self.transport.configure(&config);
+        self.config = config;
+
+        if key_changed && self.cipher.is_some() {
+            self.write_salt()?;
+        }
+
+        if changed {
+            self.write_control(Control::Compression(compression))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write(&mut self, node: u32, flags: u8, data: &[u8]) -> Result<(), Error> {
+        // control packets are small and must get through, like the close frame
+        if flags & FLAG_CONTROL != 0 && !self.establishing() {
+            return self.write_now(node, flags, data);
+        }
+
+        let unreliable = flags & FLAG_UNRELIABLE != 0;
+        if flags & FLAG_CONTROL == 0 && !self.backpressure(unreliable)? {
+            return Ok(());
+        }
+
+        // messages are deferred before they are encrypted, the remote end expects increasing nonces
+        let over_budget = self.config.bandwidth.is_some() && (!self.deferred.is_empty() || self.refill() <= 0.0);
+        if self.establishing() || over_budget {
+            if !unreliable {
+                self.deferred_bytes += data.len();
+                self.deferred.push_back((node, flags, data.to_vec()));
+            }
+            return Ok(());
+        }
+
+        self.write_now(node, flags, data)
+    }
+
+    /// Compresses and, if needed, fragments a message, then sends it.
+    fn write_now(&mut self, node: u32, mut flags: u8, data: &[u8]) -> Result<(), Error> {
+        self.last_sent = Instant::now();
+
+        let compressed = match self.config.compression {
+            Some(ref c) if self.remote_compression && data.len() >= c.threshold => {
+                Some(compress(data, c.level)).filter(|c| c.len() < data.len())
+            },
+            _ => None,
+        };
+        let data = match compressed {
+            Some(ref compressed) => {
+                flags |= FLAG_COMPRESSED;
+                compressed.as_slice()
+            },
+            None => data,
+        };
+
+        match self.fragment_capacity(flags) {
+            Some(capacity) if data.len() > capacity => self.write_fragments(node, flags, data, capacity),
+            _ => self.write_frame(node, flags, data),
+        }
+    }
+
+    /// Adds the bandwidth that was earned since the last call to the budget and returns it.
+    fn refill(&mut self) -> f64 {
+        if let Some(bandwidth) = self.config.bandwidth {
+            let bandwidth = bandwidth as f64;
+            let earned = self.budget_updated.elapsed().as_secs_f64() * bandwidth;
+            self.budget = (self.budget + earned).min(bandwidth);
+            self.budget_updated = Instant::now();
+        }
+        self.budget
+    }
+
+    /// Returns true while encryption is enabled, but the salt of the remote end has not arrived yet.
+    fn establishing(&self) -> bool {
+        self.cipher.as_ref().is_some_and(|cipher| !cipher.is_established())
+    }
+
+    /// Sends the salt of this end, unencrypted. See `Config::encryption`.
+    fn write_salt(&mut self) -> Result<(), Error> {
+        let mut control = Control::Salt(self.cipher.as_ref().unwrap().salt().to_vec());
+        let mut ser = Serializer::new(Vec::new());
+        control.reflect(&mut ser)?;
+        self.write_frame(0, FLAG_CONTROL, ser.writer.as_slice())
+    }
+
+    /// Sends deferred messages for as long as the budget allows.
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.establishing() {
+            return Ok(());
+        }
+        while !self.deferred.is_empty() && (self.config.bandwidth.is_none() || self.refill() > 0.0) {
+            let (node, flags, data) = self.deferred.pop_front().unwrap();
+            self.deferred_bytes -= data.len();
+            self.write_now(node, flags, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes that are waiting to be sent, either deferred or queued by the `Transport`.
+    fn queued(&self) -> usize {
+        self.deferred_bytes + self.transport.queued()
+    }
+
+    /// Applies the `QueuePolicy` when the send queue is above it's high-water mark.
+    /// Returns false if the message should be dropped.
+    fn backpressure(&mut self, unreliable: bool) -> Result<bool, Error> {
+        let high_water_mark = self.config.send_queue.high_water_mark;
+        let queued = self.queued();
+        if queued <= high_water_mark {
+            return Ok(true);
+        }
+
+        match self.config.send_queue.policy {
+            QueuePolicy::DropClient => {
+                self.transport.close();
+                Err(Error::LimitExceeded("send queue", queued))
+            },
+            QueuePolicy::DropUnreliable => Ok(!unreliable),
+            QueuePolicy::Block => {
+                let start = Instant::now();
+                while self.queued() > high_water_mark {
+                    if start.elapsed() >= self.config.send_queue.block_timeout {
+                        self.transport.close();
+                        return Err(Error::LimitExceeded("send queue", self.queued()));
+                    }
+                    self.poll_transport()?;
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Ok(true)
+            },
+        }
+    }
+
+    /// Returns how much data fits in a single `Packet` with `flags`, if there is a limit at all.
+    fn fragment_capacity(&self, flags: u8) -> Option<usize> {
+        let size = match (self.config.fragment_size, self.transport.fragment_size(flags & FLAG_UNRELIABLE != 0)) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b)?,
+        };
+
+        let mut overhead = 0;
+        if self.cipher.is_some() {
+            overhead += OVERHEAD;
+        }
+        if self.config.checksum {
+            overhead += 4;
+        }
+        Some(size.saturating_sub(overhead))
+    }
+
+    /// Splits `data` into fragments of at most `capacity` bytes, header included, and sends them 
+    ///  back to back, see `Config::fragment_size`.
+    fn write_fragments(&mut self, node: u32, flags: u8, data: &[u8], capacity: usize) -> Result<(), Error> {
+        let chunk = capacity.saturating_sub(FRAGMENT_HEADER);
+        if chunk == 0 {
+            return Err(Error::Custom("Fragment size too small".into()));
+        }
+
+        let count = data.len().div_ceil(chunk);
+        Limits::check("fragment count", count, u16::MAX as usize)?;
+
+        self.fragmented_sent = self.fragmented_sent.wrapping_add(1);
+
+        for (index, chunk) in data.chunks(chunk).enumerate() {
+            let mut fragment = vec![0u8; FRAGMENT_HEADER];
+            BigEndian::write_u32(&mut fragment[0..4], self.fragmented_sent);
+            BigEndian::write_u16(&mut fragment[4..6], index as u16);
+            BigEndian::write_u16(&mut fragment[6..8], count as u16);
+            fragment.extend_from_slice(chunk);
+
+            self.write_frame(node, flags | FLAG_FRAGMENT, &fragment)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts and checksums the data of a single `Packet`, then hands it to the `Transport`.
+    /// Only the salt is written before encryption is established, everything else is deferred.
+    fn write_frame(&mut self, node: u32, mut flags: u8, data: &[u8]) -> Result<(), Error> {
+        let encrypted = match self.cipher {
+            Some(ref mut cipher) if cipher.is_established() => {
+                flags |= FLAG_ENCRYPTED;
+                Some(cipher.seal(&associated_data(node, flags), data)?)
+            },
+            _ => None,
+        };
+        let data = match encrypted {
+            Some(ref encrypted) => encrypted.as_slice(),
+            None => data,
+        };
+
+        if self.config.checksum {
+            let mut data = data.to_vec();
+            let mut checksum = [0u8; 4];
+            BigEndian::write_u32(&mut checksum, crc32(node, &data));
+            data.extend_from_slice(&checksum);
+            flags |= FLAG_CHECKSUM;
+            self.send(Packet::new(node, flags, data))
+        } else {
+            self.send(Packet::new(node, flags, data.to_vec()))
+        }
+    }
+
+    fn write_unreliable(&mut self, node: u32, data: &[u8]) -> Result<(), Error> {
+        self.unreliable_sent = self.unreliable_sent.wrapping_add(1);
+
+        let mut payload = vec![0u8; 4];
+        BigEndian::write_u32(&mut payload, self.unreliable_sent);
+        payload.extend_from_slice(data);
+
+        self.write(node, FLAG_UNRELIABLE, &payload)
+    }
+
+    fn send(&mut self, packet: Packet) -> Result<(), Error> {
+        if self.config.bandwidth.is_some() {
+            self.budget -= packet.data.len() as f64;
+        }
+        self.stats.bytes_sent += packet.data.len() as u64;
+        self.stats.packets_sent += 1;
+        self.stats.last_sent = Some(Instant::now());
+
+        if packet.is_unreliable() {
+            self.transport.send_unreliable(packet)
+        } else {
+            self.transport.send(packet)
+        }
+    }
+
+    fn write_control(&mut self, mut control: Control) -> Result<(), Error> {
+        let mut ser = Serializer::new(Vec::new());
+        control.reflect(&mut ser)?;
+        self.write(0, FLAG_CONTROL, ser.writer.as_slice())
+    }
+
+    /// Sends deferred messages regardless of the budget, then a close frame and closes the `Transport`.
+    fn close(&mut self, reason: DisconnectReason) {
+        self.config.bandwidth = None;
+        self.flush().ok();
+        self.write_control(Control::Close(reason)).ok();
+        self.transport.close();
+
+        let queued = self.transport.queued();
+        self.state.lock().unwrap().close(CloseReason::Local(reason), queued);
+    }
+
+    fn poll(&mut self) -> Result<(), Error> {
+        self.poll_transport()?;
+        self.keepalive()
+    }
+
+    /// Polls the `Transport` and processes everything that was received.
+    fn poll_transport(&mut self) -> Result<(), Error> {
+        self.flush()?;
+
+        let mut received = Vec::new();
+        let result = self.transport.poll(&mut received);
+        if !received.is_empty() {
+            self.state.lock().unwrap().connected();
+        }
+        for packet in received {
+            self.stats.bytes_received += packet.data.len() as u64;
+            self.stats.packets_received += 1;
+            self.stats.last_received = Some(Instant::now());
+
+            self.receive(packet)?;
+        }
+        // messages that waited for the salt of the remote end
+        self.flush()?;
+        result?;
+        self.transport.status()
+    }
+
+    /// Blocks for a short while or until more data has been received.
+    fn wait(&mut self) -> Result<(), Error> {
+        self.transport.wait(Duration::from_millis(10))
+    }
+
+    /// Queues a received packet for `recv`, unless it's a control packet. 
+    /// Unreliable packets that are stale or can not be unpacked are dropped.
+    fn receive(&mut self, packet: Packet) -> Result<(), Error> {
+        let mut packet = match self.unpack(packet) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if packet.flags & FLAG_UNRELIABLE != 0 {
+            if packet.data.len() < 4 {
+                return Ok(());
+            }
+            let sequence = BigEndian::read_u32(&packet.data[..4]);
+            let last = self.unreliable_received.entry(packet.node).or_insert(0);
+            if (sequence.wrapping_sub(*last) as i32) <= 0 {
+                return Ok(());
+            }
+            *last = sequence;
+            packet.data.drain(..4);
+        }
+
+        if packet.flags & FLAG_CONTROL == 0 {
+            self.packets.push_back(packet);
+            return Ok(());
+        }
+
+        let mut control = Control::default();
+        control.reflect(&mut Deserializer::new(packet.data.as_slice()))?;
+
+        match control {
+            Control::Close(reason) => {
+                self.state.lock().unwrap().close(CloseReason::Remote(reason), 0);
+                Err(Error::Disconnected(reason))
+            },
+            Control::Heartbeat => Ok(()),
+            Control::Compression(enabled) => {
+                self.remote_compression = enabled;
+                Ok(())
+            },
+            Control::Ping(time) => self.write_control(Control::Pong(time, unix_micros())),
+            Control::Pong(time, remote_time) => {
+                self.measure(time, remote_time);
+                Ok(())
+            },
+            // `unpack_data` consumes salts when encryption is enabled, so the remote end 
+            //  uses a key while we don't
+            Control::Salt(_) => Err(Error::DecryptionFailed),
+        }
+    }
+
+    /// Verifies, decrypts, reassembles and decompresses the data of a received packet.
+    /// Returns `None` for fragments of a message that is not complete yet.
+    /// Errors for unreliable packets are not fatal, these packets are dropped by returning `None`.
+    fn unpack(&mut self, packet: Packet) -> Result<Option<Packet>, Error> {
+        let unreliable = packet.flags & FLAG_UNRELIABLE != 0;
+        match self.unpack_data(packet) {
+            Ok(packet) => Ok(packet),
+            Err(_) if unreliable => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn unpack_data(&mut self, mut packet: Packet) -> Result<Option<Packet>, Error> {
+        if packet.flags & FLAG_CHECKSUM != 0 {
+            if packet.data.len() < 4 {
+                return Err(Error::Custom("Corrupt Packet".into()));
+            }
+            let len = packet.data.len() - 4;
+            let expected = BigEndian::read_u32(&packet.data[len..]);
+            packet.data.truncate(len);
+            let actual = crc32(packet.node, &packet.data);
+            if actual != expected {
+                return Err(Error::ChecksumMismatch(expected, actual));
+            }
+        }
+
+        let encrypted = packet.flags & FLAG_ENCRYPTED != 0;
+        match self.cipher {
+            Some(ref mut cipher) if encrypted => {
+                let aad = associated_data(packet.node, packet.flags);
+                packet.data = cipher.open(&aad, &packet.data, packet.flags & FLAG_UNRELIABLE != 0)?;
+            },
+            Some(ref mut cipher) if packet.flags & FLAG_CONTROL != 0 => {
+                let mut control = Control::default();
+                control.reflect(&mut Deserializer::new(packet.data.as_slice()))?;
+                match control {
+                    Control::Salt(salt) => cipher.establish(&salt)?,
+                    _ => return Err(Error::DecryptionFailed),
+                }
+                return Ok(None);
+            },
+            None if !encrypted => (),
+            _ => return Err(Error::DecryptionFailed),
+        }
+        self.last_recv = Instant::now();
+
+        if packet.flags & FLAG_FRAGMENT != 0 {
+            packet = match self.reassemble(packet)? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+        }
+
+        if packet.flags & FLAG_COMPRESSED != 0 {
+            packet.data = decompress(&packet.data, self.config.limits.max_packet_size)?;
+        }
+
+        Ok(Some(packet))
+    }
+
+    /// Stores a fragment, returning the reassembled message once all of it's fragments are in.
+    /// Only one reliable message per connection and one unreliable message per node can be 
+    ///  incomplete, since the fragments of a message are sent back to back. 
+    /// A message may not exceed `Limits::max_packet_size`, and everything that is held 
+    ///  for incomplete messages combined may not exceed `Limits::max_buffered`.
+    fn reassemble(&mut self, packet: Packet) -> Result<Option<Packet>, Error> {
+        if packet.data.len() <= FRAGMENT_HEADER {
+            return Err(Error::Custom("Corrupt Packet".into()));
+        }
+        let id = BigEndian::read_u32(&packet.data[0..4]);
+        let index = BigEndian::read_u16(&packet.data[4..6]) as usize;
+        let count = BigEndian::read_u16(&packet.data[6..8]) as usize;
+        let flags = packet.flags & !(FLAG_FRAGMENT | FLAG_CHECKSUM | FLAG_ENCRYPTED);
+        let chunk = packet.data.len() - FRAGMENT_HEADER;
+        if index >= count {
+            return Err(Error::Custom("Corrupt Packet".into()));
+        }
+
+        // all fragments but the last one have the same size, so the size of the message is known 
+        //  up front, every fragment carries at least one byte
+        let max = self.config.limits.max_packet_size;
+        if index + 1 < count {
+            Limits::check("packet size", (count - 1) * chunk + 1, max)?;
+        } else {
+            Limits::check("packet size", count - 1 + chunk, max)?;
+        }
+
+        if !self.fragmented_received.contains_key(&id) {
+            if flags & FLAG_UNRELIABLE != 0 {
+                // a newer unreliable message makes incomplete older ones for the same node stale
+                self.fragmented_received.retain(|_, f| f.node != packet.node || f.flags & FLAG_UNRELIABLE == 0);
+            } else if self.fragmented_received.values().any(|f| f.flags & FLAG_UNRELIABLE == 0) {
+                return Err(Error::Custom("Corrupt Packet".into()));
+            }
+        }
+
+        let mut buffered: usize = self.fragmented_received.values().map(Fragments::buffered).sum();
+        buffered += chunk;
+        if !self.fragmented_received.contains_key(&id) {
+            buffered += count * mem::size_of::<Option<Vec<u8>>>();
+        }
+        Limits::check("buffered fragments", buffered, self.config.limits.max_buffered)?;
+
+        let complete = {
+            let fragments = self.fragmented_received.entry(id).or_insert_with(|| Fragments {
+                node: packet.node,
+                flags,
+                parts: vec![None; count],
+                received: 0,
+                size: 0,
+            });
+            if fragments.node != packet.node || fragments.flags != flags || fragments.parts.len() != count {
+                return Err(Error::Custom("Corrupt Packet".into()));
+            }
+
+            if fragments.parts[index].is_none() {
+                fragments.size += packet.data.len() - FRAGMENT_HEADER;
+                fragments.parts[index] = Some(packet.data[FRAGMENT_HEADER..].to_vec());
+                fragments.received += 1;
+            }
+            fragments.received == count
+        };
+
+        if complete {
+            let fragments = self.fragmented_received.remove(&id).unwrap();
+            let mut data = Vec::with_capacity(fragments.size);
+            for part in fragments.parts {
+                data.extend_from_slice(&part.unwrap());
+            }
+            Ok(Some(Packet::new(fragments.node, fragments.flags, data)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Updates the latency using a pong for the ping that was sent at `time`. 
+    /// The round-trip time and jitter are smoothed like TCP does, see RFC 6298.
+    fn measure(&mut self, time: u64, remote_time: u64) {
+        let now = self.epoch.elapsed().as_micros() as u64;
+        if time > now {
+            return;
+        }
+        let sample = Duration::from_micros(now - time);
+
+        // the remote end replied halfway through the round trip
+        let remote_now = remote_time + sample.as_micros() as u64 / 2;
+        let offset = remote_now as i64 - unix_micros() as i64;
+
+        self.latency = Some(match self.latency.take() {
+            None => Latency {
+                rtt: sample,
+                jitter: sample / 2,
+                clock_offset: offset,
+            },
+            Some(latency) => {
+                let deviation = latency.rtt.abs_diff(sample);
+                Latency {
+                    rtt: latency.rtt * 7 / 8 + sample / 8,
+                    jitter: latency.jitter * 3 / 4 + deviation / 4,
+                    clock_offset: latency.clock_offset + (offset - latency.clock_offset) / 8,
+                }
+            },
+        });
+    }
+
+    /// Sends a heartbeat when needed and checks if the remote end timed out.
+    fn keepalive(&mut self) -> Result<(), Error> {
+        if let Some(timeout) = self.config.timeout {
+            if self.last_recv.elapsed() >= timeout {
+                self.close(DisconnectReason::Timeout);
+                return Err(Error::Disconnected(DisconnectReason::Timeout));
+            }
+        }
+
+        // nothing can be sent until encryption is established
+        if self.establishing() {
+            return Ok(());
+        }
+
+        if let Some(ping) = self.config.ping {
+            if self.last_ping.is_none_or(|last| last.elapsed() >= ping) {
+                self.last_ping = Some(Instant::now());
+                let time = self.epoch.elapsed().as_micros() as u64;
+                self.write_control(Control::Ping(time))?;
+            }
+        }
+
+        if let Some(heartbeat) = self.config.heartbeat {
+            if self.last_sent.elapsed() >= heartbeat {
+                self.write_control(Control::Heartbeat)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The current unix time in microseconds.
+fn unix_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// The header fields that are authenticated along with encrypted data.
+/// `FLAG_CHECKSUM` is excluded, since the checksum is added after encryption.
+fn associated_data(node: u32, flags: u8) -> [u8; 5] {
+    let mut aad = [0u8; 5];
+    BigEndian::write_u32(&mut aad[..4], node);
+    aad[4] = flags & !FLAG_CHECKSUM;
+    aad
+}
+
+/// Deflates `data`, preceded by it's length so the remote end can check it before inflating.
+fn compress(data: &[u8], level: u8) -> Vec<u8> {
+    let mut compressed = vec![0u8; 4];
+    BigEndian::write_u32(&mut compressed, data.len() as u32);
+    compressed.extend_from_slice(&compress_to_vec(data, level));
+    compressed
+}
+
+/// Inflates data written by `compress`, refusing to produce more than `max` bytes.
+fn decompress(data: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+    if data.len() < 4 {
+        return Err(Error::Custom("Corrupt Packet".into()));
+    }
+    let len = BigEndian::read_u32(&data[..4]) as usize;
+    Limits::check("packet size", len, max)?;
+
+    let mut decompressed = vec![0u8; len];
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+    match inflate(&mut decompressor, &data[4..], &mut decompressed, 0, flags) {
+        (TINFLStatus::Done, _, written) if written == len => Ok(decompressed),
+        _ => Err(Error::Custom("Corrupt Packet".into())),
+    }
+}
+
+/// Computes the CRC32 (IEEE) of the big endian bytes of `node` followed by `data`.
+fn crc32(node: u32, data: &[u8]) -> u32 {
+    let mut node_bytes = [0u8; 4];
+    BigEndian::write_u32(&mut node_bytes, node);
+
+    let mut crc = !0u32;
+    for &byte in node_bytes.iter().chain(data.iter()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    fn fragment(node: u32, flags: u8, id: u32, index: u16, count: u16, len: usize) -> Packet {
+        let mut data = vec![0u8; FRAGMENT_HEADER + len];
+        BigEndian::write_u32(&mut data[0..4], id);
+        BigEndian::write_u16(&mut data[4..6], index);
+        BigEndian::write_u16(&mut data[6..8], count);
+        Packet::new(node, flags | FLAG_FRAGMENT, data)
+    }
+
+    fn conn(max_packet_size: usize, max_buffered: usize) -> Connection {
+        let (conn, _) = Connection::pair(1);
+        let mut config = Config::default();
+        config.limits.max_packet_size = max_packet_size;
+        config.limits.max_buffered = max_buffered;
+        conn.configure(config);
+        conn
+    }
+
+    #[test]
+    fn refuses_oversized_messages_before_allocating() {
+        let conn = conn(10_000, 1 << 20);
+        let mut inner = conn.inner.lock().unwrap();
+
+        let result = inner.reassemble(fragment(1, 0, 1, 0, u16::MAX, 1000));
+        assert!(matches!(result, Err(Error::LimitExceeded("packet size", _))));
+        assert!(inner.fragmented_received.is_empty());
+    }
+
+    #[test]
+    fn counts_the_parts_against_the_buffer_limit() {
+        let conn = conn(1 << 20, 10_000);
+        let mut inner = conn.inner.lock().unwrap();
+
+        // the last fragment only tells the message has `count` parts
+        let result = inner.reassemble(fragment(1, 0, 1, u16::MAX - 1, u16::MAX, 1));
+        assert!(matches!(result, Err(Error::LimitExceeded("buffered fragments", _))));
+        assert!(inner.fragmented_received.is_empty());
+    }
+
+    #[test]
+    fn keeps_one_incomplete_message_per_channel() {
+        let conn = conn(1 << 20, 1 << 20);
+        let mut inner = conn.inner.lock().unwrap();
+
+        // a newer unreliable message for the same node replaces the older one
+        for id in 1..10 {
+            assert!(inner.reassemble(fragment(1, FLAG_UNRELIABLE, id, 0, 2, 10)).unwrap().is_none());
+        }
+        assert!(inner.reassemble(fragment(2, FLAG_UNRELIABLE, 10, 0, 2, 10)).unwrap().is_none());
+        assert_eq!(inner.fragmented_received.len(), 2);
+
+        assert!(inner.reassemble(fragment(1, 0, 11, 0, 2, 10)).unwrap().is_none());
+        assert!(inner.reassemble(fragment(1, 0, 12, 0, 2, 10)).is_err());
+
+        let packet = inner.reassemble(fragment(1, 0, 11, 1, 2, 5)).unwrap().unwrap();
+        assert_eq!(packet.data.len(), 15);
+        assert!(inner.reassemble(fragment(1, 0, 12, 0, 2, 10)).unwrap().is_none());
+    }
+
+    /// A writer that returns `WouldBlock` until it's opened, and a reader without data.
+    struct Blocked {
+        open: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Blocked {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if !self.open.load(AtomicOrdering::Relaxed) {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl NonBlocking for Blocked {
+        fn set_nonblocking(&self, _: bool) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Blocked {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn polled_connections_flush_while_closing() {
+        let open = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let blocked = || Blocked { open: open.clone(), written: written.clone() };
+        let conn = Connection::polled(blocked(), blocked(), 1).unwrap();
+
+        conn.send(1, b"hello");
+        conn.close(DisconnectReason::Kicked);
+        conn.poll();
+        assert!(matches!(conn.state(), ConnectionState::Closing));
+        assert!(written.lock().unwrap().is_empty());
+
+        open.store(true, AtomicOrdering::Relaxed);
+        conn.poll();
+        assert!(matches!(conn.state(), ConnectionState::Closed(CloseReason::Local(DisconnectReason::Kicked))));
+        assert!(!written.lock().unwrap().is_empty());
+        assert_eq!(conn.stats().queued, 0);
+    }
+
+    #[test]
+    fn udp_transport_sends_unreliable_packets_as_datagrams() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+
+        let (x, y) = ChannelTransport::pair();
+        let mut a = UdpTransport::new(x, a).unwrap();
+        let mut b = UdpTransport::new(y, b).unwrap();
+
+        a.send(Packet::new(1, 0, vec![1])).unwrap();
+        a.send_unreliable(Packet::new(2, FLAG_UNRELIABLE, vec![2])).unwrap();
+        // too large for a datagram, so it goes over the reliable transport
+        a.send_unreliable(Packet::new(3, FLAG_UNRELIABLE, vec![3; MAX_DATAGRAM_SIZE])).unwrap();
+
+        let mut packets = Vec::new();
+        for _ in 0..100 {
+            b.poll(&mut packets).unwrap();
+            if packets.len() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        packets.sort_by_key(|p| p.node);
+        assert_eq!(packets.iter().map(|p| p.node).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(packets[1].data, vec![2]);
+        assert!(packets[1].is_unreliable());
+    }
+
+    #[test]
+    fn close_reaches_the_remote_end_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        // any writer works, only a `TcpStream` or `UnixStream` is shut down to stop the reader thread
+        let local = Connection::new(std::io::BufWriter::new(stream.try_clone().unwrap()), stream, 0);
+        let remote = Connection::new(accepted.try_clone().unwrap(), accepted, 0);
+
+        local.send(1, b"hello");
+        local.close(DisconnectReason::Kicked);
+        assert_eq!(remote.recv_blocking().unwrap().data, b"hello");
+        assert!(remote.recv_blocking().is_none());
+        assert!(matches!(remote.status(), Err(Error::Disconnected(DisconnectReason::Kicked))));
+    }
+
+    fn encrypted(transport: ChannelTransport) -> Connection {
+        let conn = Connection::from_transport(transport, 1);
+        conn.configure(Config {
+            encryption: Some(Key([7; 32])),
+            ..Config::default()
+        });
+        conn
+    }
+
+    /// Passes everything `from` received on to `to`, changing the last byte of messages for node 1.
+    fn relay(from: &mut ChannelTransport, to: &mut ChannelTransport, tamper: bool) {
+        let mut packets = Vec::new();
+        from.poll(&mut packets).unwrap();
+        for mut packet in packets {
+            if tamper && packet.node == 1 {
+                *packet.data.last_mut().unwrap() ^= 1;
+            }
+            to.send(packet).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuses_unencrypted_packets() {
+        let (ours, mut theirs) = ChannelTransport::pair();
+        let conn = encrypted(ours);
+
+        theirs.send(Packet::new(1, 0, b"hello".to_vec())).unwrap();
+        assert!(conn.recv().is_none());
+        assert!(matches!(conn.status(), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn refuses_tampered_packets() {
+        let (a, mut a_relay) = ChannelTransport::pair();
+        let (mut b_relay, b) = ChannelTransport::pair();
+        let (a, b) = (encrypted(a), encrypted(b));
+
+        // exchange the salts
+        relay(&mut a_relay, &mut b_relay, false);
+        relay(&mut b_relay, &mut a_relay, false);
+        a.poll();
+        b.poll();
+
+        a.send(1, b"hello");
+        relay(&mut a_relay, &mut b_relay, true);
+        assert!(b.recv().is_none());
+        assert!(matches!(b.status(), Err(Error::DecryptionFailed)));
     }
 }
@@ -0,0 +1,221 @@
+use super::*;
+use std::fmt;
+use byteorder::{ByteOrder, BigEndian};
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// Size of the nonce used by XChaCha20-Poly1305.
+const NONCE: usize = 24;
+
+/// Size of the random salt that each end of a `Connection` contributes to the session keys.
+pub const SALT: usize = NONCE / 2;
+
+/// Number of bytes that encryption adds to the data of a `Packet`: the counter and the tag.
+pub const OVERHEAD: usize = 8 + 16;
+
+/// Label of the session keys, for `Packet`s sent by the end whose salt comes first.
+const SESSION_LABEL: &[u8] = b"ggnet session key, sender to receiver";
+
+/// A pre-shared 256 bit key, see `Config::encryption`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key(pub [u8; 32]);
+
+impl fmt::Debug for Key {
+    /// Never prints the key itself, since `Config` is likely to end up in logs.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+/// Encrypts and authenticates the data of `Packet`s using XChaCha20-Poly1305.
+/// Both ends pick a random salt and send it to the other end. Each direction then uses it's own 
+///  session key, derived from the pre-shared key and both salts, so `Packet`s recorded from one 
+///  `Connection` can not be replayed into another one. 
+/// Every `Packet` carries a counter that is used as the nonce. The counter of the remote end must 
+///  increase with every `Packet`, so recorded `Packet`s can not be replayed within the same 
+///  `Connection` either. Unreliable `Packet`s are checked separately, since they may overtake reliable ones.
+pub struct Cipher {
+    key: Key,
+    salt: [u8; SALT],
+    remote_salt: Option<[u8; SALT]>,
+    /// The session keys for sending and receiving, once the salt of the remote end is known.
+    session: Option<(XChaCha20Poly1305, XChaCha20Poly1305)>,
+    sent: u64,
+    received: u64,
+    received_unreliable: u64,
+}
+
+impl Cipher {
+    pub fn new(key: Key) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT];
+        getrandom::getrandom(&mut salt)
+            .map_err(|e| Error::Custom(format!("Unable to generate salt: {}", e)))?;
+
+        Ok(Self {
+            key,
+            salt,
+            remote_salt: None,
+            session: None,
+            sent: 0,
+            received: 0,
+            received_unreliable: 0,
+        })
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// The salt of this end, which has to be sent to the remote end.
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// Returns true once the salt of the remote end was received.
+    pub fn is_established(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Derives the session keys from the salt of the remote end. 
+    /// The first salt is kept, a different salt received later fails with `Error::DecryptionFailed`.
+    pub fn establish(&mut self, remote_salt: &[u8]) -> Result<(), Error> {
+        if remote_salt.len() != SALT {
+            return Err(Error::DecryptionFailed);
+        }
+        if let Some(ref salt) = self.remote_salt {
+            return if salt[..] == remote_salt[..] { Ok(()) } else { Err(Error::DecryptionFailed) };
+        }
+
+        let mut salt = [0u8; SALT];
+        salt.copy_from_slice(remote_salt);
+        self.remote_salt = Some(salt);
+        self.session = Some((
+            derive(&self.key, &self.salt, &salt)?,
+            derive(&self.key, &salt, &self.salt)?,
+        ));
+        Ok(())
+    }
+
+    /// Encrypts `data`, returning the counter followed by the ciphertext and tag.
+    /// `aad` is authenticated, but not encrypted.
+    pub fn seal(&mut self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        let aead = match self.session {
+            Some((ref send, _)) => send,
+            None => return Err(Error::Custom("Encryption is not established".into())),
+        };
+        self.sent += 1;
+
+        let mut counter = [0u8; 8];
+        BigEndian::write_u64(&mut counter, self.sent);
+
+        let sealed = aead.encrypt(
+            &nonce(self.sent),
+            Payload { msg: data, aad },
+        ).map_err(|_| Error::Custom("Encryption failed".into()))?;
+
+        let mut result = counter.to_vec();
+        result.extend_from_slice(&sealed);
+        Ok(result)
+    }
+
+    /// Decrypts data produced by `seal` on the remote end.
+    /// Fails with `Error::DecryptionFailed` if the data or `aad` were tampered with,
+    ///  if the data was received before or if it was sealed for another `Connection`.
+    pub fn open(&mut self, aad: &[u8], data: &[u8], unreliable: bool) -> Result<Vec<u8>, Error> {
+        let aead = match self.session {
+            Some((_, ref receive)) => receive,
+            None => return Err(Error::DecryptionFailed),
+        };
+        if data.len() < 8 {
+            return Err(Error::DecryptionFailed);
+        }
+
+        let counter = BigEndian::read_u64(&data[..8]);
+        let received = if unreliable { self.received_unreliable } else { self.received };
+        if counter <= received {
+            return Err(Error::DecryptionFailed);
+        }
+
+        let result = aead.decrypt(
+            &nonce(counter),
+            Payload { msg: &data[8..], aad },
+        ).map_err(|_| Error::DecryptionFailed)?;
+
+        if unreliable {
+            self.received_unreliable = counter;
+        } else {
+            self.received = counter;
+        }
+        Ok(result)
+    }
+}
+
+/// The nonce for a counter. Session keys are never reused, so the counter alone is unique.
+fn nonce(counter: u64) -> chacha20poly1305::XNonce {
+    let mut nonce = [0u8; NONCE];
+    BigEndian::write_u64(&mut nonce[NONCE - 8..], counter);
+    chacha20poly1305::XNonce::from(nonce)
+}
+
+/// Derives the session key for `Packet`s sent by the end with salt `first` to the end with salt `second`, 
+///  using HKDF-SHA256 (RFC 5869) over the pre-shared key with both salts, in that order, as the salt.
+fn derive(key: &Key, first: &[u8; SALT], second: &[u8; SALT]) -> Result<XChaCha20Poly1305, Error> {
+    let mut salts = [0u8; 2 * SALT];
+    salts[..SALT].copy_from_slice(first);
+    salts[SALT..].copy_from_slice(second);
+
+    let mut session = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salts), &key.0)
+        .expand(SESSION_LABEL, &mut session)
+        .map_err(|_| Error::Custom("Key derivation failed".into()))?;
+    Ok(XChaCha20Poly1305::new(&chacha20poly1305::Key::from(session)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Cipher, Cipher) {
+        let mut a = Cipher::new(Key([7; 32])).unwrap();
+        let mut b = Cipher::new(Key([7; 32])).unwrap();
+        let salt = a.salt().to_vec();
+        a.establish(b.salt()).unwrap();
+        b.establish(&salt).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut a, mut b) = pair();
+        let sealed = a.seal(b"aad", b"hello").unwrap();
+        assert_eq!(b.open(b"aad", &sealed, false).unwrap(), b"hello");
+        assert!(b.open(b"other", &a.seal(b"aad", b"hello").unwrap(), false).is_err());
+    }
+
+    #[test]
+    fn rejects_replay_within_a_session() {
+        let (mut a, mut b) = pair();
+        let first = a.seal(b"", b"first").unwrap();
+        let second = a.seal(b"", b"second").unwrap();
+        b.open(b"", &second, false).unwrap();
+        assert!(b.open(b"", &second, false).is_err());
+        assert!(b.open(b"", &first, false).is_err());
+    }
+
+    #[test]
+    fn rejects_replay_into_another_session() {
+        let (mut a, _) = pair();
+        let recorded = a.seal(b"", b"recorded").unwrap();
+
+        let (_, mut b) = pair();
+        assert!(matches!(b.open(b"", &recorded, false), Err(Error::DecryptionFailed)));
+    }
+
+    #[test]
+    fn keeps_the_first_remote_salt() {
+        let (_, mut b) = pair();
+        assert!(b.establish(&[1; SALT]).is_err());
+    }
+}
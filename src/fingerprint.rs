@@ -0,0 +1,177 @@
+use super::*;
+use std::any::TypeId;
+use std::marker::PhantomData;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian};
+
+/// Types that implement this trait have a stable hash of their structure: member names, 
+///  member types and enum variants, recursively. Values are not part of the fingerprint.
+/// Two builds agree on the fingerprint of a type exactly when they agree on it's wire format.
+/// `#[derive(Reflect)]` implements this trait, 
+///  types that implement `Reflect<V>` manually should implement it as well.
+pub trait Fingerprint {
+    /// Returns the fingerprint of `Self`.
+    fn fingerprint() -> u64;
+}
+
+/// Hasher used to compute fingerprints (64 bit FNV-1a).
+pub struct Fingerprinter {
+    hash: u64,
+}
+
+impl Default for Fingerprinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fingerprinter {
+    pub fn new() -> Self {
+        Self {
+            hash: 0xcbf29ce484222325,
+        }
+    }
+
+    /// Mixes `bytes` into the hash.
+    /// Use `write_str` for names, so names that are concatenations of others don't collide.
+    pub fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.hash ^= *b as u64;
+            self.hash = self.hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Mixes a number, like the number of members of a type, into the hash.
+    pub fn write_u32(&mut self, value: u32) {
+        let mut bytes = [0u8; 4];
+        BigEndian::write_u32(&mut bytes, value);
+        self.write(&bytes);
+    }
+
+    /// Mixes the length of `name` and then `name` itself into the hash.
+    pub fn write_str(&mut self, name: &str) {
+        self.write_u32(name.len() as u32);
+        self.write(name.as_bytes());
+    }
+
+    /// Mixes the fingerprint of a member into the hash.
+    pub fn write_fingerprint(&mut self, fingerprint: u64) {
+        let mut bytes = [0u8; 8];
+        BigEndian::write_u64(&mut bytes, fingerprint);
+        self.write(&bytes);
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+/// Computes the fingerprint of `T` with `compute` the first time it's needed, and remembers it. 
+/// Fingerprints are checked every time a `Node` is serialized or deserialized, 
+///  `#[derive(Reflect)]` uses this so the structure of a type is only hashed once.
+pub fn cache_fingerprint<T: 'static, F: FnOnce() -> u64>(compute: F) -> u64 {
+    static CACHE: OnceLock<RwLock<HashMap<TypeId, u64>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+
+    let id = TypeId::of::<T>();
+    if let Some(&fingerprint) = cache.read().unwrap().get(&id) {
+        return fingerprint;
+    }
+
+    // computed without holding the lock, members are cached on the way
+    let fingerprint = compute();
+    cache.write().unwrap().insert(id, fingerprint);
+    fingerprint
+}
+
+macro_rules! primitive {
+    ($t:ty) => (
+        impl Fingerprint for $t {
+            fn fingerprint() -> u64 {
+                let mut hasher = Fingerprinter::new();
+                hasher.write_str(stringify!($t));
+                hasher.finish()
+            }
+        }
+    )
+}
+
+primitive!{ u8 }
+primitive!{ i8 }
+primitive!{ u16 }
+primitive!{ i16 }
+primitive!{ u32 }
+primitive!{ i32 }
+primitive!{ f32 }
+primitive!{ u64 }
+primitive!{ i64 }
+primitive!{ f64 }
+primitive!{ bool }
+primitive!{ String }
+primitive!{ Duration }
+
+impl<T: Fingerprint> Fingerprint for Vec<T> {
+    fn fingerprint() -> u64 {
+        let mut hasher = Fingerprinter::new();
+        hasher.write_str("Vec");
+        hasher.write_fingerprint(T::fingerprint());
+        hasher.finish()
+    }
+}
+
+impl<K: Fingerprint, V: Fingerprint> Fingerprint for HashMap<K, V> {
+    fn fingerprint() -> u64 {
+        let mut hasher = Fingerprinter::new();
+        hasher.write_str("HashMap");
+        hasher.write_fingerprint(K::fingerprint());
+        hasher.write_fingerprint(V::fingerprint());
+        hasher.finish()
+    }
+}
+
+impl<T: Fingerprint> Fingerprint for Option<T> {
+    fn fingerprint() -> u64 {
+        let mut hasher = Fingerprinter::new();
+        hasher.write_str("Option");
+        hasher.write_fingerprint(T::fingerprint());
+        hasher.finish()
+    }
+}
+
+impl<T> Fingerprint for PhantomData<T> {
+    fn fingerprint() -> u64 {
+        let mut hasher = Fingerprinter::new();
+        hasher.write_str("PhantomData");
+        hasher.finish()
+    }
+}
+
+macro_rules! fingerprint_tuple {
+    ($(($($x:ident),*))*) => {$(
+        impl<$($x),*> Fingerprint for ($($x),*) where
+            $($x: Fingerprint, )*
+        {
+            fn fingerprint() -> u64 {
+                let mut hasher = Fingerprinter::new();
+                hasher.write_str("tuple");
+                $(hasher.write_fingerprint($x::fingerprint());)*
+                hasher.finish()
+            }
+        }
+    )*}
+}
+
+fingerprint_tuple!{
+    (A, B)
+    (A, B, C)
+    (A, B, C, D)
+    (A, B, C, D, E)
+    (A, B, C, D, E, F)
+    (A, B, C, D, E, F, G)
+    (A, B, C, D, E, F, G, H)
+    (A, B, C, D, E, F, G, H, I)
+    (A, B, C, D, E, F, G, H, I, J)
+    (A, B, C, D, E, F, G, H, I, J, K)
+    (A, B, C, D, E, F, G, H, I, J, K, L)
+}
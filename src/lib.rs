@@ -1,11 +1,22 @@
 extern crate byteorder;
+extern crate miniz_oxide;
+extern crate chacha20poly1305;
+extern crate hkdf;
+extern crate sha2;
+extern crate getrandom;
+extern crate sha1_smol;
 #[macro_use] extern crate ggnet_derive;
 
 mod visitor;
 mod node;
 mod connection;
+mod transport;
+mod udp;
+mod websocket;
 mod rpc;
 mod server;
+mod fingerprint;
+mod encryption;
 
 use std::collections::HashMap;
 use std::any::Any;
@@ -16,12 +27,17 @@ use std::io::{Read,Write};
 use visitor::encode::{encode, decode};
 
 pub use visitor::serializer::Serializer;
-pub use visitor::deserializer::Deserializer;
+pub use visitor::deserializer::{Deserializer, Limits};
 pub use visitor::printer::Printer;
 pub use node::{NodeBase, Node, Tag, TagServer, TagClient};
 pub use rpc::*;
 pub use connection::*;
+pub use transport::*;
+pub use udp::*;
+pub use websocket::{WebSocket, WebSocketWriter, WebSocketReader};
 pub use server::*;
+pub use fingerprint::*;
+pub use encryption::Key;
 
 /// Error type for ggnet related errors.
 #[derive(Debug)]
@@ -29,6 +45,33 @@ pub enum Error {
     Custom(std::string::String),
     IOError(std::io::Error),
     UTFError(std::string::FromUtf8Error),
+    Disconnected(DisconnectReason),
+    ProtocolMismatch(u32, u32),
+    SchemaMismatch(u64, u64),
+    /// A `Packet` was received with a checksum that doesn't match its contents: (expected, actual).
+    ChecksumMismatch(u32, u32),
+    /// A value received from the wire exceeds one of the configured `Limits`: (limit, value).
+    LimitExceeded(&'static str, usize),
+    /// An encrypted `Packet` could not be authenticated, or an unencrypted `Packet` was received
+    ///  while encryption is enabled.
+    DecryptionFailed,
+}
+
+impl Clone for Error {
+    /// Clones the error. An `IOError` is recreated from it's kind and message.
+    fn clone(&self) -> Self {
+        match *self {
+            Error::Custom(ref s) => Error::Custom(s.clone()),
+            Error::IOError(ref e) => Error::IOError(std::io::Error::new(e.kind(), e.to_string())),
+            Error::UTFError(ref e) => Error::UTFError(e.clone()),
+            Error::Disconnected(reason) => Error::Disconnected(reason),
+            Error::ProtocolMismatch(a, b) => Error::ProtocolMismatch(a, b),
+            Error::SchemaMismatch(a, b) => Error::SchemaMismatch(a, b),
+            Error::ChecksumMismatch(a, b) => Error::ChecksumMismatch(a, b),
+            Error::LimitExceeded(name, value) => Error::LimitExceeded(name, value),
+            Error::DecryptionFailed => Error::DecryptionFailed,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
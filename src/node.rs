@@ -41,11 +41,13 @@ pub trait NodeBase<T: Tag>: Any {
     fn id(&self) -> u32;
     fn context(&self) -> Arc<Mutex<NodeContext<T>>>;
     fn send(&self, BufferSerializer);
-    fn recv_rpc<'a>(&mut self, BufferDeserializer);
+    fn send_unreliable(&self, msg: BufferSerializer);
+    fn recv_rpc<'a>(&mut self, BufferDeserializer) -> Result<(), Error>;
     fn recv_update<'a>(&mut self, BufferDeserializer);
     fn add_ref(&mut self, parent: u32);
     fn remove_ref(&mut self, parent: u32);
     fn add_connections(&self, target: &mut HashSet<Connection>); 
+    fn reaches(&self, conn: &Connection) -> bool;
 }
 
 /// Private functions for `Node<T,G>`
@@ -57,6 +59,8 @@ pub trait NewNode<T: CallUpdate + CallRPC + Default + Any + Reflect<Refresher>,
 pub struct NodeContext<T: Tag> {
     nodes: HashMap<u32, Box<NodeBase<T>>>,
     next: u32,
+    /// The client whose rpc is being performed, see `Node::caller`.
+    caller: Option<Caller>,
 }
 
 struct NodeInner {
@@ -168,11 +172,12 @@ impl<T, G> NewNode<T,G> for Node<T,G> where
 
 impl<W, T, G> Reflect<Serializer<W>> for Node<T,G> where
     W: Write,
-    T: 'static + CallUpdate + CallRPC + Reflect<Serializer<W>> + Reflect<Refresher>,
+    T: 'static + CallUpdate + CallRPC + Reflect<Serializer<W>> + Reflect<Refresher> + Fingerprint,
     G: 'static + Tag
 {
     fn reflect(&mut self, visit: &mut Serializer<W>) -> Result<(), Error> {
         self.id.reflect(visit)?;
+        T::fingerprint().reflect(visit)?;
 
         assert!(self.id > 0);
 
@@ -192,11 +197,12 @@ impl<W, T, G> Reflect<Serializer<W>> for Node<T,G> where
 
 impl<R, T, G> Reflect<Deserializer<R>> for Node<T,G> where
     R: Read,
-    T: 'static + CallUpdate + CallRPC + Reflect<Deserializer<R>> + Reflect<Refresher>,
+    T: 'static + CallUpdate + CallRPC + Reflect<Deserializer<R>> + Reflect<Refresher> + Fingerprint,
     G: Tag,
 {
     fn reflect(&mut self, visit: &mut Deserializer<R>) -> Result<(), Error> {
         self.id.reflect(visit)?;
+        check_fingerprint::<T, _>(visit)?;
 
         assert!(self.id > 0);
 
@@ -217,7 +223,7 @@ impl<R, T, G> Reflect<Deserializer<R>> for Node<T,G> where
             self.context.as_ref().unwrap().lock().unwrap().insert(self.id, new_node);
             result            
         });
-        let shared_inner = shared_inner.as_any().downcast_ref::<Self>().unwrap();
+        let shared_inner = downcast::<Self, G>(&*shared_inner)?;
         self.inner = shared_inner.inner.clone();
         self.val = shared_inner.val.clone();
         // set node owner
@@ -237,13 +243,15 @@ impl<R, T, G> Reflect<Deserializer<R>> for Node<T,G> where
 
 impl<V, T, G> Reflect<Updater<V>> for Node<T, G> where
     V: Visitor,
-    T: 'static + CallUpdate + CallRPC + Reflect<Updater<V>> + Reflect<Refresher> + Reflect<V>,
+    T: 'static + CallUpdate + CallRPC + Reflect<Updater<V>> + Reflect<Refresher> + Reflect<V> + Fingerprint,
     G: Tag,
     u32: Reflect<Updater<V>>,
+    u64: Reflect<Updater<V>>,
 {
     fn reflect(&mut self, visit: &mut Updater<V>) -> Result<(), Error> {
         let old_id = self.id;
         self.id.reflect(visit)?;
+        check_fingerprint::<T, _>(visit)?;
 
         println!("reflect node {}", self.id);
 
@@ -265,7 +273,7 @@ impl<V, T, G> Reflect<Updater<V>> for Node<T, G> where
                 self.context.as_ref().unwrap().lock().unwrap().insert(self.id, new_node);
                 result            
             });
-            let shared_inner = shared_inner.as_any().downcast_ref::<Self>().unwrap();
+            let shared_inner = downcast::<Self, G>(&*shared_inner)?;
             self.inner = shared_inner.inner.clone();
             self.val = shared_inner.val.clone();
             // set node owner
@@ -288,6 +296,40 @@ impl<V, T, G> Reflect<Updater<V>> for Node<T, G> where
     }
 }
 
+impl<T, G> Fingerprint for Node<T, G> where
+    T: CallUpdate + CallRPC + Default + Any + Reflect<Refresher> + Fingerprint,
+    G: Tag,
+{
+    fn fingerprint() -> u64 {
+        // the tag is left out, so server and client nodes have the same fingerprint
+        let mut hasher = Fingerprinter::new();
+        hasher.write_str("Node");
+        hasher.write_fingerprint(T::fingerprint());
+        hasher.finish()
+    }
+}
+
+/// Reflects the fingerprint of `T`. When deserializing, 
+///  an error is returned if the fingerprint that was read does not match `T`.
+fn check_fingerprint<T: Fingerprint, V: Visitor>(visit: &mut V) -> Result<(), Error> where
+    u64: Reflect<V>
+{
+    let expected = T::fingerprint();
+    let mut fingerprint = expected;
+    fingerprint.reflect(visit)?;
+    if fingerprint != expected {
+        return Err(Error::SchemaMismatch(expected, fingerprint));
+    }
+    Ok(())
+}
+
+/// Downcasts a node from the `NodeContext` to the type that is expected by the caller.
+fn downcast<N: Any, G: Tag>(node: &NodeBase<G>) -> Result<&N, Error> {
+    node.as_any().downcast_ref::<N>().ok_or_else(|| {
+        Error::Custom(format!("Node {} does not have the expected type", node.id()))
+    })
+}
+
 impl<T, G> Reflect<Printer> for Node<T, G> where
     T: 'static + CallUpdate + CallRPC + Reflect<Printer> + Reflect<Refresher>,
     G: Tag,
@@ -389,6 +431,12 @@ impl<X> Node<X, TagServer> where
         NodeContext::<TagServer>::create(&self.context(), content).convert()
     }
 
+    /// Returns the client that called the rpc that is currently being performed. 
+    /// Returns `None` outside of rpc bodies, like when the `Node` is modified by game code.
+    pub fn caller(&self) -> Option<Caller> {
+        self.context().lock().unwrap().caller.clone()
+    }
+
     /// Update all members.
     pub fn resync(&mut self) {
         let mut op = UpdateOp::Replace;
@@ -401,6 +449,23 @@ impl<X> Node<X, TagServer> where
         self.send(updater.unwrap());
     }
 
+    /// Update all members through the unreliable channel of each `Connection`, see 
+    ///  `Connection::send_unreliable`. Updates can get lost, but a lost update is fixed by the next 
+    ///  one, which makes this suitable for fast-changing state like positions that is resynced every tick.
+    /// Only use this for nodes that don't contain other `Node`s, structural changes should stay reliable.
+    /// A datagram that arrives late still overwrites members that were modified reliably 
+    ///  after it was sent, use `member_modified_unreliable` for nodes that mix both.
+    pub fn resync_unreliable(&mut self) {
+        let mut op = UpdateOp::Replace;
+        let mut ser = BufferSerializer::with_current_node(vec![], self.id());
+
+        op.reflect(&mut ser).unwrap();
+        String::from("root").reflect(&mut ser).unwrap();
+        let mut updater = Updater::new_replace(self.id(), ser);
+        self.val.lock().unwrap().reflect(&mut updater).unwrap();
+        self.send_unreliable(updater.unwrap());
+    }
+
     /// Update a single member with name `tag`.
     pub fn member_modified(&mut self, mut tag: String) {
         let mut op = UpdateOp::Update;
@@ -413,6 +478,21 @@ impl<X> Node<X, TagServer> where
         self.send(updater.unwrap());
     }
 
+    /// Update a single member with name `tag` through the unreliable channel of each `Connection`, 
+    ///  like `resync_unreliable`. Members that are updated this way should not be modified reliably.
+    /// Unreliable updates of a `Node` share their ordering, an update is dropped when one for 
+    ///  another member of the same `Node` overtakes it. Send them every tick, so that is fixed as well.
+    pub fn member_modified_unreliable(&mut self, mut tag: String) {
+        let mut op = UpdateOp::Update;
+        let mut ser = BufferSerializer::with_current_node(vec![], self.id());
+
+        op.reflect(&mut ser).unwrap();
+        tag.reflect(&mut ser).unwrap();
+        let mut updater = Updater::new_update(self.id(), ser, tag);
+        self.val.lock().unwrap().reflect(&mut updater).unwrap();
+        self.send_unreliable(updater.unwrap());
+    }
+
     /// Push a new element to the `Vec<T>` member with name `tag`.
     pub fn member_vec_push<T>(&mut self, mut tag: String, val: T) where
         T: Reflect<Serializer<Vec<u8>>> + Any
@@ -529,7 +609,9 @@ impl<T, G> NodeBase<G> for WeakNode<T, G> where
 
     fn send(&self, _: BufferSerializer) { unimplemented!(); }
 
-    fn recv_rpc<'a>(&mut self, msg: BufferDeserializer) { self.as_box().recv_rpc(msg); }
+    fn send_unreliable(&self, msg: BufferSerializer) { self.as_box().send_unreliable(msg); }
+
+    fn recv_rpc<'a>(&mut self, msg: BufferDeserializer) -> Result<(), Error> { self.as_box().recv_rpc(msg) }
 
     fn recv_update<'a>(&mut self, msg: BufferDeserializer) { self.as_box().recv_update(msg); }
 
@@ -538,6 +620,8 @@ impl<T, G> NodeBase<G> for WeakNode<T, G> where
     fn remove_ref(&mut self, _: u32) { unimplemented!(); }
 
     fn add_connections(&self, target: &mut HashSet<Connection>) { self.as_box().add_connections(target); }
+
+    fn reaches(&self, conn: &Connection) -> bool { self.as_box().reaches(conn) }
 }
 
 impl<T, G> NodeBase<G> for Node<T, G> where
@@ -561,8 +645,15 @@ impl<T, G> NodeBase<G> for Node<T, G> where
         }
     }
 
-    fn recv_rpc<'a>(&mut self, msg: BufferDeserializer) {
-        T::call_rpc(self, msg);
+    fn send_unreliable(&self, msg: BufferSerializer) {
+        let inner = self.inner.lock().unwrap();
+        for conn in inner.conns.iter() {
+            conn.send_unreliable(self.id, msg.writer.as_slice());
+        }
+    }
+
+    fn recv_rpc<'a>(&mut self, msg: BufferDeserializer) -> Result<(), Error> {
+        T::call_rpc(self, msg)
     }
 
     fn recv_update<'a>(&mut self, msg: BufferDeserializer) {
@@ -612,6 +703,12 @@ impl<T, G> NodeBase<G> for Node<T, G> where
             target.insert(c.clone());
         }
     }
+
+    /// Returns true if the `Node` is replicated to `conn`.
+    fn reaches(&self, conn: &Connection) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.root.as_ref() == Some(conn) || inner.conns.contains(conn)
+    }
 }
 
 pub struct Borrow<'a, T: 'a> {
@@ -649,6 +746,7 @@ impl<G: Tag> NodeContext<G> {
         Self {
             nodes: HashMap::new(),
             next: 1,
+            caller: None,
         }
     } 
 
@@ -691,4 +789,11 @@ impl<G: Tag> NodeContext<G> {
     pub fn gc(&mut self, id: u32) {
         self.nodes.remove(&id);
     }
+
+    /// Sets the client whose rpc is being performed and returns the previous one. 
+    /// The returned `Caller` must be dropped after the context is unlocked, 
+    ///  since it may hold the last reference to a root `Node`.
+    pub fn set_caller(&mut self, caller: Option<Caller>) -> Option<Caller> {
+        replace(&mut self.caller, caller)
+    }
 }
\ No newline at end of file
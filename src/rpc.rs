@@ -7,7 +7,8 @@ use std::io::Cursor;
 pub trait CallRPC {
     /// Process rpc call encoded in the `message` parameter. 
     /// The `node` parameter is expected to be a mutable reference in the form `&mut Node<T, TagServer>`.
-    fn call_rpc(node: &mut Any, message: Deserializer<Cursor<Vec<u8>>>);
+    /// Fails if the message can not be decoded or names an rpc that doesn't exist.
+    fn call_rpc(node: &mut Any, message: Deserializer<Cursor<Vec<u8>>>) -> Result<(), Error>;
 }
 
 /// Implement the `CallRPC` trait for any number of types. 
@@ -33,8 +34,8 @@ pub trait CallRPC {
 /// !         //  the type `Node`. It behaves as if it were `&mut Node<Foo, TagServer>`.
 /// !         // RPC's are not allowed to have a return type.
 /// !         rpc hello_foo(x: Node, greeting: String) {
-/// !             // print a message on the server
-/// !             println!("Hello from client: {}", greeting);
+/// !             // print a message on the server, along with the connection that called the rpc
+/// !             println!("Hello from client {}: {}", x.caller().unwrap().id(), greeting);
 /// !             // modify the node
 /// !             x.as_mut().foo = greeting;
 /// !             // notify subscribed clients of this change
@@ -83,16 +84,20 @@ macro_rules! rpc {
         }
 
         impl<$($bound : $bound_ty),*> CallRPC for $self {
-            fn call_rpc(node: &mut ::std::any::Any, mut msg: Deserializer<::std::io::Cursor<::std::vec::Vec<u8>>>) {
+            fn call_rpc(
+                node: &mut ::std::any::Any, 
+                mut msg: Deserializer<::std::io::Cursor<::std::vec::Vec<u8>>>
+            ) -> ::std::result::Result<(), $crate::Error> {
                 let mut rpc_id = String::default();
-                rpc_id.reflect(&mut msg).unwrap();
+                rpc_id.reflect(&mut msg)?;
                 $(if rpc_id == stringify!($fn_name) {
                     // decode function arguments
-                    $(let mut $arg: $arg_ty = ::std::default::Default::default(); $arg.reflect(&mut msg).unwrap();)*
+                    $(let mut $arg: $arg_ty = ::std::default::Default::default(); $arg.reflect(&mut msg)?;)*
                     // evaluate function body
                     node.downcast_mut::<Node<$self, TagServer>>().unwrap().$fn_name($($arg),*);
+                    Ok(())
                 } else)* {
-                    panic!("requested rpc not found");
+                    Err($crate::Error::Custom(format!("Unknown rpc: {}", rpc_id)))
                 }
             }
         })*
@@ -1,16 +1,140 @@
 use super::*;
 use std::ops::{Deref,DerefMut};
 use std::io::Cursor;
+use std::time::{Duration, Instant, SystemTime};
 use visitor::updater::CallUpdate;
 use visitor::refresher::Refresher;
 use node::{NodeBase, NodeContext, NewNode};
 
+/// Version of the protocol spoken between `Server` and `Client`. 
+/// A `Client` refuses to connect to a `Server` with a different version.
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// Sent by the `Client` to node 0 as soon as it connects, see `Client::resume`.
+#[derive(Reflect, Default)]
+struct Hello {
+    /// The session to resume, 0 for a new session.
+    session: u64,
+}
+
+/// Sent by the `Server` to node 0, before the root `Node` of a new or resumed session.
+#[derive(Reflect, Default)]
+struct Handshake {
+    version: u32,
+    fingerprint: u64,
+    session: u64,
+}
+
+/// Attaches the root `Node` of a `Session` to a new connection and serializes it.
+type Attach = Box<Fn(&Connection) -> Result<Vec<u8>, Error>>;
+
+/// Starts a new session on a connection, see `Pending`.
+type Start = Box<FnOnce(&mut Server, Connection)>;
+
+/// Decides if a client may call an rpc, see `Server::set_rpc_filter`.
+type RpcFilter = Box<Fn(&Caller, u32, &str) -> bool>;
+
+/// Everything the contained value of a root `Node` needs, so the `Server` can create, 
+///  serialize and fingerprint it. Implemented for every type that meets the bounds, 
+///  which the `Reflect` derive and the `rpc!` macro take care of.
+pub trait RootNode: CallRPC + CallUpdate + Default + Any + Reflect<Serializer<Vec<u8>>> + Fingerprint + Reflect<Refresher> {}
+
+impl<T> RootNode for T where 
+    T: CallRPC + CallUpdate + Default + Any + Reflect<Serializer<Vec<u8>>> + Fingerprint + Reflect<Refresher> {}
+
+/// A connection managed by the `Server` and the root `Node` that belongs to it.
+struct Session {
+    conn: Connection,
+    root: Box<NodeBase<TagServer>>,
+    token: u64,
+    fingerprint: u64,
+    attach: Attach,
+}
+
+/// A connection that waits for the client before it is given a root `Node`, see `Server::add_connection`.
+struct Pending {
+    conn: Connection,
+    start: Start,
+    /// Whether the client may resume a session, which only it's `Hello` tells.
+    resumable: bool,
+}
+
+/// Something that happened to a connection managed by the `Server`, see `Server::events`.
+/// Connections are identified by the id of their `Connection`.
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    /// A connection was added and has been sent it's root `Node`.
+    ClientConnected(usize),
+    /// A connection was cleaned up by `Server::update`, or taken over by another connection 
+    ///  that resumed it's session.
+    ClientDisconnected(usize, CloseReason),
+    /// A connection resumed the session of an earlier connection: the new id and the old id.
+    /// The root `Node` of the old connection now belongs to the new one. 
+    /// The new connection is not reported as `ClientConnected`.
+    ClientResumed(usize, usize),
+    /// An rpc received from a connection could not be performed, because it was malformed 
+    ///  or named an rpc that doesn't exist. 
+    /// The connection is closed with `DisconnectReason::ProtocolError`.
+    RpcFailed(usize, Error),
+    /// An rpc received from a connection was refused: the connection id, the `Node` id and the 
+    ///  name of the rpc. Rpcs are refused when the `Node` is not replicated to the connection, 
+    ///  or when the filter set with `Server::set_rpc_filter` says so. 
+    /// The rpc is dropped, but the connection stays open, since the client may have called it 
+    ///  right before the `Node` was removed from it's view.
+    RpcDenied(usize, u32, String),
+    /// A connection could not be accepted, because no session token could be generated for it.
+    /// The connection is closed with `DisconnectReason::Refused`.
+    ClientRefused(usize, Error),
+}
+
+/// The client that called an rpc, see `Node::caller`.
+pub struct Caller {
+    conn: Connection,
+    root: Box<NodeBase<TagServer>>,
+}
+
+impl Caller {
+    /// Returns the id of the calling `Connection`.
+    pub fn id(&self) -> usize {
+        self.conn.id()
+    }
+
+    /// Returns the calling `Connection`.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Returns the root `Node` of the calling connection, 
+    ///  or `None` if it's not a `Node<T, _>`. See `Server::client_root`.
+    pub fn root<T>(&self) -> Option<Node<T, TagServer>> where
+        T: 'static + CallUpdate + CallRPC + Default + Any + Reflect<Refresher>
+    {
+        downcast_root(&*self.root)
+    }
+}
+
+impl Clone for Caller {
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            root: self.root.as_box(),
+        }
+    }
+}
+
 /// Manages `Connection`s and `Node`s. Does not manage sockets, 
 /// this is up to the user as ggnet is net API agnostic.
 pub struct Server {
     context: Arc<Mutex<NodeContext<TagServer>>>,
-    clients: Vec<(Connection, Box<NodeBase<TagServer>>)>,
+    clients: Vec<Session>,
+    pending: Vec<Pending>,
+    /// Sessions of dropped connections that can still be resumed, and when they were dropped.
+    detached: Vec<(Session, Instant)>,
+    session_grace: Option<Duration>,
+    rpc_filter: Option<RpcFilter>,
+    events: Vec<ServerEvent>,
     next_connection_id: usize,
+    config: Config,
 }
 
 /// A managed connection to a `Server`. Does not manage sockets, 
@@ -21,15 +145,27 @@ pub struct Client<T: Default + CallUpdate + CallRPC + Any + Reflect<Refresher>>
     conn: Connection,
     root: Node<T, TagClient>,
     context: Arc<Mutex<NodeContext<TagClient>>>,
+    session: u64,
 }
 
 impl Server {
     /// Initializes a new server.
     pub fn new() -> Server {
+        Self::with_config(Config::default())
+    }
+
+    /// Initializes a new server that applies `config` to all connections it manages.
+    pub fn with_config(config: Config) -> Server {
         Self {
             context: Arc::new(Mutex::new(NodeContext::new())),
             clients: Vec::new(),
+            pending: Vec::new(),
+            detached: Vec::new(),
+            session_grace: None,
+            rpc_filter: None,
+            events: Vec::new(),
             next_connection_id: 1,
+            config,
         }
     }
 
@@ -38,18 +174,113 @@ impl Server {
     ///  which correspond to the sending and receiving end of a two way socket.
     /// The connection is also expected to have it's own root `Node`,
     ///  for which the user needs to supply a suitable contained value.
+    /// Like `Connection::new`, this uses a reader and a writer thread per connection, 
+    ///  see `add_polled_client` for a connection that uses no threads of it's own.
     pub fn add_client<W, R, T>(&mut self, w: W, r: R, root: T)  where
-        W: 'static + Write,
+        W: 'static + Write + 
+                     Send,
         R: 'static + Read + 
                      Send,
-        T: 'static + CallRPC + 
-                     CallUpdate + 
-                     Default + 
-                     Any + 
-                     Reflect<Serializer<Vec<u8>>> + 
-                     Reflect<Refresher>,
+        T: 'static + RootNode,
+    {
+        let config = self.config.clone();
+        self.add_client_with_config(w, r, root, config);
+    }
+
+    /// Like `add_client`, but applies `config` to the connection instead of the `Config` 
+    ///  the `Server` was created with, for example to only compress traffic to some clients.
+    pub fn add_client_with_config<W, R, T>(&mut self, w: W, r: R, root: T, config: Config)  where
+        W: 'static + Write + 
+                     Send,
+        R: 'static + Read + 
+                     Send,
+        T: 'static + RootNode,
     {
         let conn = Connection::new(w, r, self.next_connection_id);
+        self.add_connection(conn, root, config, true);
+    }
+
+    /// Manage a new connection without dedicated reader and writer threads. 
+    /// The supplied `Write` and `Read` implementations are switched to non-blocking mode, 
+    ///  all reading and writing is then driven by `Server::update`. See `Connection::polled`.
+    /// Like `add_client`, the connection gets it's own root `Node`.
+    pub fn add_polled_client<W, R, T>(&mut self, w: W, r: R, root: T) -> Result<(), Error> where
+        W: 'static + Write + NonBlocking,
+        R: 'static + Read + NonBlocking,
+        T: 'static + RootNode,
+    {
+        let conn = Connection::polled(w, r, self.next_connection_id)?;
+        self.add_connection(conn, root, self.config.clone(), true);
+        Ok(())
+    }
+
+    /// Manage a new connection on top of a custom `Transport`, see `Connection::from_transport`.
+    /// Like `add_client`, the connection gets it's own root `Node`.
+    pub fn add_transport_client<X, T>(&mut self, transport: X, root: T) where
+        X: 'static + Transport,
+        T: 'static + RootNode,
+    {
+        let conn = Connection::from_transport(transport, self.next_connection_id);
+        self.add_connection(conn, root, self.config.clone(), true);
+    }
+
+    /// Manage a new in-process connection created with `Connection::pair`.
+    /// One end is managed by the `Server`, the other end is returned and can be used to 
+    ///  initialize a `Client`. Like `add_client`, the connection gets it's own root `Node`.
+    /// Since the pair can't drop, the connection always starts a new session right away, 
+    ///  which lets the `Client` be created without updating the `Server` first.
+    pub fn add_loopback_client<T>(&mut self, root: T) -> Connection where
+        T: 'static + RootNode,
+    {
+        let (conn, remote) = Connection::pair(self.next_connection_id);
+        self.add_connection(conn, root, self.config.clone(), false);
+        remote
+    }
+
+    /// Sets up a new connection. Remote connections that may resume a session wait for the `Hello` 
+    ///  of the client in `Server::update` before they are given a root `Node`. Those that use 
+    ///  compression wait for the `Hello` or for the client to announce compression, which it does 
+    ///  before it's `Hello`, so the root `Node` is compressed as well.
+    fn add_connection<T>(&mut self, conn: Connection, root: T, config: Config, remote: bool) where
+        T: 'static + RootNode,
+    {
+        let resumable = remote && self.session_grace.is_some();
+        let wait = resumable || (remote && config.compression.is_some());
+        conn.configure(config);
+        self.next_connection_id += 1;
+
+        if wait {
+            self.pending.push(Pending {
+                conn,
+                start: Box::new(move |server: &mut Server, conn| server.start_session(conn, root)),
+                resumable,
+            });
+        } else {
+            self.start_session(conn, root);
+        }
+    }
+
+    /// Gives a connection a new session and sends it the handshake and it's new root `Node`.
+    fn start_session<T>(&mut self, conn: Connection, root: T) where
+        T: 'static + RootNode,
+    {
+        let mut token = [0u8; 8];
+        if let Err(e) = getrandom::getrandom(&mut token) {
+            conn.close(DisconnectReason::Refused);
+            let error = Error::Custom(format!("Unable to generate session token: {}", e));
+            self.events.push(ServerEvent::ClientRefused(conn.id(), error));
+            return;
+        }
+
+        let mut handshake = Handshake {
+            version: PROTOCOL_VERSION,
+            fingerprint: T::fingerprint(),
+            session: u64::from_be_bytes(token),
+        };
+        let mut ser = Serializer::new(Vec::new());
+        handshake.reflect(&mut ser).unwrap();
+        conn.send(0, ser.writer.as_slice());
+
         let mut root = self.make_node(root);
 
         let mut ser = Serializer::new(Vec::new());
@@ -57,8 +288,42 @@ impl Server {
         root.reflect(&mut ser).unwrap();
         conn.send(root.id(), ser.writer.as_slice());
 
-        self.clients.push((conn.clone(), root.as_box()));
-        self.next_connection_id += 1;
+        let attached = root.clone();
+        self.clients.push(Session {
+            conn: conn.clone(),
+            root: root.as_box(),
+            token: handshake.session,
+            fingerprint: handshake.fingerprint,
+            attach: Box::new(move |conn| {
+                let mut root = attached.clone();
+                root.set_root(conn.clone());
+                root.reflect(&mut Refresher)?;
+
+                let mut ser = Serializer::new(Vec::new());
+                root.reflect(&mut ser)?;
+                Ok(ser.writer)
+            }),
+        });
+        self.events.push(ServerEvent::ClientConnected(conn.id()));
+    }
+
+    /// Keep the root `Node` of a connection that dropped for `grace`, so the client can pick up 
+    ///  where it left off using `Client::resume`. Connections that were closed on purpose, 
+    ///  like with `DisconnectReason::Kicked`, can not be resumed. `None` disables this, which is the default.
+    /// While this is set, connections added afterwards are only sent a root `Node` once the client 
+    ///  has said which session it wants, so the `Server` has to be updated while `Client::new` 
+    ///  or `Client::resume` wait for it. Connections that close before that are not reported.
+    pub fn set_session_grace(&mut self, grace: Option<Duration>) {
+        self.session_grace = grace;
+    }
+
+    /// Sets a check that is performed before every rpc, in addition to the check that the `Node` 
+    ///  is replicated to the calling connection. The filter receives the caller, the `Node` id 
+    ///  and the name of the rpc. Rpcs for which it returns false are refused, see `ServerEvent::RpcDenied`.
+    pub fn set_rpc_filter<F>(&mut self, filter: F) where
+        F: 'static + Fn(&Caller, u32, &str) -> bool
+    {
+        self.rpc_filter = Some(Box::new(filter));
     }
 
     /// Create a new `Node` managed by this `Server`. 
@@ -71,19 +336,209 @@ impl Server {
 
     /// Updates the `Server`. Processes received messages from the managed connections and 
     ///  cleans up inactive connections and their root nodes after that.
+    /// Connections that have not been heard from within the configured timeout are dropped here.
+    /// Connections added with `add_polled_client` do all of their reading and writing here.
+    /// What happened to the connections is reported by `Server::events`.
     pub fn update(&mut self) {
-        let clients = &mut self.clients;
-        let context = &self.context;
+        let mut i = 0;
+        while i < self.pending.len() {
+            let packet = match self.pending[i].conn.recv() {
+                Some(packet) => packet,
+                None => {
+                    let pending = &self.pending[i];
+                    if pending.conn.status().is_err() {
+                        self.pending.remove(i);
+                    } else if !pending.resumable && pending.conn.remote_compression() {
+                        let pending = self.pending.remove(i);
+                        (pending.start)(self, pending.conn);
+                    } else {
+                        i += 1;
+                    }
+                    continue;
+                },
+            };
 
-        for &mut (ref mut conn, _) in clients.iter_mut() {
-            conn.recv().map(|packet| {
-                let mut node = context.lock().unwrap().get(packet.node).unwrap().as_box();
-                node.recv_rpc(Deserializer::new(Cursor::new(packet.data)));
-            });
+            let pending = self.pending.remove(i);
+            let mut hello = Hello::default();
+            let mut de = Deserializer::new(Cursor::new(packet.data));
+            de.set_limits(pending.conn.config().limits);
+            match hello.reflect(&mut de) {
+                Ok(()) if packet.node == 0 && pending.resumable => self.resume(pending, hello.session),
+                Ok(()) if packet.node == 0 => (pending.start)(self, pending.conn),
+                _ => pending.conn.close(DisconnectReason::ProtocolError),
+            }
+        }
+
+        {
+            let context = &self.context;
+            let events = &mut self.events;
+            let filter = &self.rpc_filter;
+
+            for session in self.clients.iter_mut() {
+                // skip the `Hello` of a connection that could not resume a session
+                let packet = std::iter::from_fn(|| session.conn.recv()).find(|packet| packet.node != 0);
+                packet.map(|packet| {
+                    // the name of the rpc is needed before the message is handed to the `Node`
+                    let limits = session.conn.config().limits;
+                    let rpc = rpc_name(&packet.data, &limits);
+                    let mut de = Deserializer::new(Cursor::new(packet.data));
+                    de.set_limits(limits);
+
+                    let id = packet.node;
+                    let node = context.lock().unwrap().get(id);
+                    let caller = Caller {
+                        conn: session.conn.clone(),
+                        root: session.root.as_box(),
+                    };
+
+                    let result = rpc.and_then(|rpc| {
+                        // clients can only call rpcs on nodes that were replicated to them
+                        let allowed = node.as_ref().is_some_and(|node| node.reaches(&caller.conn)) && 
+                            filter.as_ref().is_none_or(|filter| filter(&caller, id, &rpc));
+                        if !allowed {
+                            events.push(ServerEvent::RpcDenied(caller.id(), id, rpc));
+                            return Ok(());
+                        }
+
+                        context.lock().unwrap().set_caller(Some(caller));
+                        let result = node.unwrap().recv_rpc(de);
+                        let caller = context.lock().unwrap().set_caller(None);
+                        drop(caller);
+                        result
+                    });
+                    if let Err(e) = result {
+                        session.conn.close(DisconnectReason::ProtocolError);
+                        events.push(ServerEvent::RpcFailed(session.conn.id(), e));
+                    }
+                });
+            }
         }
 
-        clients.retain(|(ref c, _)| c.status().is_ok());
+        let grace = self.session_grace;
+        let mut i = 0;
+        while i < self.clients.len() {
+            match self.clients[i].conn.status() {
+                Ok(()) => i += 1,
+                Err(e) => {
+                    let session = self.clients.remove(i);
+                    let reason = session.conn.close_reason().unwrap_or_else(|| CloseReason::Error(e.clone()));
+                    self.events.push(ServerEvent::ClientDisconnected(session.conn.id(), reason));
+
+                    let dropped = match e {
+                        Error::Disconnected(reason) => reason == DisconnectReason::Timeout,
+                        _ => true,
+                    };
+                    if grace.is_some() && dropped {
+                        self.detached.push((session, Instant::now()));
+                    }
+                },
+            }
+        }
+        self.detached.retain(|&(_, dropped)| grace.is_some_and(|grace| dropped.elapsed() < grace));
     }
+
+    /// Moves the session with `token` to a pending connection and sends it the existing root `Node`. 
+    /// If the session still has a live connection, that connection is closed. 
+    /// For unknown tokens a new session is started instead.
+    fn resume(&mut self, pending: Pending, token: u64) {
+        let conn = pending.conn;
+        let mut session = if let Some(i) = self.detached.iter().position(|(s, _)| s.token == token) {
+            self.detached.remove(i).0
+        } else if let Some(i) = self.clients.iter().position(|s| s.token == token && s.conn != conn) {
+            // the remote end may not have noticed yet that the old connection dropped
+            let session = self.clients.remove(i);
+            session.conn.close(DisconnectReason::Shutdown);
+            let reason = session.conn.close_reason().unwrap();
+            self.events.push(ServerEvent::ClientDisconnected(session.conn.id(), reason));
+            session
+        } else {
+            return (pending.start)(self, conn);
+        };
+
+        let data = match (session.attach)(&conn) {
+            Ok(data) => data,
+            Err(_) => return (pending.start)(self, conn),
+        };
+
+        let mut handshake = Handshake {
+            version: PROTOCOL_VERSION,
+            fingerprint: session.fingerprint,
+            session: token,
+        };
+        let mut ser = Serializer::new(Vec::new());
+        handshake.reflect(&mut ser).unwrap();
+        conn.send(0, ser.writer.as_slice());
+        conn.send(session.root.id(), data.as_slice());
+
+        self.events.push(ServerEvent::ClientResumed(conn.id(), session.conn.id()));
+
+        session.conn = conn;
+        self.clients.push(session);
+    }
+
+    /// Returns what happened to the managed connections since the last call, oldest first. 
+    /// Events accumulate until they are taken here.
+    pub fn events(&mut self) -> Vec<ServerEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Returns the ids of all connections managed by this `Server`, in the order they were added.
+    /// Connections that died are included until they are cleaned up by `Server::update`.
+    pub fn clients(&self) -> Vec<usize> {
+        self.clients.iter().map(|s| s.conn.id()).collect()
+    }
+
+    /// Returns the root `Node` of the connection with `id`. 
+    /// Returns `None` if there is no such connection or if it's root `Node` is not a `Node<T, _>`.
+    pub fn client_root<T>(&self, id: usize) -> Option<Node<T, TagServer>> where
+        T: 'static + CallUpdate + CallRPC + Default + Any + Reflect<Refresher>
+    {
+        let session = self.clients.iter().find(|s| s.conn.id() == id)?;
+        downcast_root(&*session.root)
+    }
+
+    /// Closes the connection with `id` on purpose, see `Connection::close`. 
+    /// The connection and it's root `Node` are cleaned up right away and the session can not be resumed.
+    /// Returns false if there is no such connection.
+    pub fn kick(&mut self, id: usize, reason: DisconnectReason) -> bool {
+        let index = match self.clients.iter().position(|s| s.conn.id() == id) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let session = self.clients.remove(index);
+        session.conn.close(reason);
+        let reason = session.conn.close_reason().unwrap();
+        self.events.push(ServerEvent::ClientDisconnected(id, reason));
+        true
+    }
+
+    /// Returns the traffic statistics of all connections managed by this `Server` combined.
+    /// Connections that were cleaned up by `Server::update` are no longer included.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for session in self.clients.iter() {
+            stats.merge(&session.conn.stats());
+        }
+        stats
+    }
+}
+
+/// Reads the name of the rpc that an rpc message starts with, see `rpc!`.
+fn rpc_name(data: &[u8], limits: &Limits) -> Result<String, Error> {
+    let mut de = Deserializer::new(Cursor::new(data));
+    de.set_limits(limits.clone());
+
+    let mut rpc = String::default();
+    rpc.reflect(&mut de)?;
+    Ok(rpc)
+}
+
+/// Returns a typed handle to a root `Node`, if it has the type `Node<T, TagServer>`.
+fn downcast_root<T>(root: &NodeBase<TagServer>) -> Option<Node<T, TagServer>> where
+    T: 'static + CallUpdate + CallRPC + Default + Any + Reflect<Refresher>
+{
+    root.as_any().downcast_ref::<Node<T, TagServer>>().cloned()
 }
 
 impl<T> Client<T> where
@@ -92,30 +547,121 @@ impl<T> Client<T> where
        Default + 
        Any + 
        Reflect<Deserializer<Cursor<Vec<u8>>>> + 
+       Fingerprint + 
        Reflect<Refresher>
 {
     /// Initialize a new connection to the `Server`. 
     /// The supplied connection should be a live connection,
     ///  but it should not have been used for any ggnet traffic yet. 
+    /// Fails with `Error::ProtocolMismatch` or `Error::SchemaMismatch` if the `Server` uses a 
+    ///  different `PROTOCOL_VERSION` or a root type with a different layout than `T`. 
+    /// In that case the connection is closed with `DisconnectReason::ProtocolError`.
     pub fn new(conn: Connection) -> Result<Self, Error> {
+        Self::resume(conn, 0)
+    }
+
+    /// Like `Client::new`, but asks the `Server` to resume the session with `token`, which 
+    ///  was returned by `Client::session` on a connection that dropped. 
+    /// If the `Server` still has the session, the `Client` gets the existing root `Node`. 
+    /// Otherwise a new session is started and `Client::session` returns a different token. 
+    /// See `Server::set_session_grace`.
+    pub fn resume(conn: Connection, token: u64) -> Result<Self, Error> {
         let context = Arc::new(Mutex::new(NodeContext::new()));
 
+        let mut hello = Hello { session: token };
+        let mut ser = Serializer::new(Vec::new());
+        hello.reflect(&mut ser)?;
+        conn.send(0, ser.writer.as_slice());
+
+        let result = Self::handshake(&conn);
+        if result.is_err() {
+            conn.close(DisconnectReason::ProtocolError);
+            return Err(result.err().unwrap());
+        }
+        let session = result.unwrap();
+
         let packet = conn.recv_blocking();
         let packet = packet.ok_or_else(|| conn.status().err().unwrap())?;
 
-        let mut de = Deserializer::new(Cursor::new(packet.data));
+        let root = Self::attach_root(&conn, &context, packet.node, packet.data)?;
+
+        Ok(Self { conn, root, context, session })
+    }
+
+    /// Returns the token of the session with the `Server`, which can be used to resume it later.
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Deserializes a root `Node` that was received on `conn`.
+    fn attach_root(
+        conn: &Connection, 
+        context: &Arc<Mutex<NodeContext<TagClient>>>, 
+        id: u32, 
+        data: Vec<u8>
+    ) -> Result<Node<T, TagClient>, Error> {
+        let mut de = Deserializer::new(Cursor::new(data));
+        de.set_limits(conn.config().limits);
         de.attach_context(context.clone());
 
-        let mut root = Node::new(packet.node, T::default(), context.clone());
+        let mut root = Node::new(id, T::default(), context.clone());
         root.set_root(conn.clone());
         
-        context.lock().unwrap().insert(packet.node, root.clone());
+        context.lock().unwrap().insert(id, root.clone());
         
-        root.reflect(&mut de).unwrap();
+        root.reflect(&mut de)?;
         
         assert!(root.id() > 0);
 
-        Ok(Self { conn, root, context })
+        Ok(root)
+    }
+
+    /// Like `Client::new`, but applies `config` to the connection first. 
+    /// Settings that need both ends to agree, like compression, should be set this way so they are 
+    ///  in effect before the root `Node` is received.
+    /// With `Config::encryption` or `Config::compression` the `Server` only sends the root after 
+    ///  it has received the salt or the compression announcement of the client, 
+    ///  so it has to be updated while this blocks. 
+    /// When both run on the same thread, configure the `Connection`, update the `Server` 
+    ///  and then call `Client::new` instead.
+    pub fn with_config(conn: Connection, config: Config) -> Result<Self, Error> {
+        conn.configure(config);
+        Self::new(conn)
+    }
+
+    fn handshake(conn: &Connection) -> Result<u64, Error> {
+        let packet = conn.recv_blocking();
+        let packet = packet.ok_or_else(|| conn.status().err().unwrap())?;
+
+        if packet.node != 0 {
+            return Err(Error::Custom("Expected handshake".into()));
+        }
+
+        let mut handshake = Handshake::default();
+        let mut de = Deserializer::new(Cursor::new(packet.data));
+        de.set_limits(conn.config().limits);
+        handshake.reflect(&mut de)?;
+
+        if handshake.version != PROTOCOL_VERSION {
+            return Err(Error::ProtocolMismatch(PROTOCOL_VERSION, handshake.version));
+        }
+
+        let fingerprint = T::fingerprint();
+        if handshake.fingerprint != fingerprint {
+            return Err(Error::SchemaMismatch(fingerprint, handshake.fingerprint));
+        }
+
+        Ok(handshake.session)
+    }
+
+    /// Returns the smoothed round-trip time to the server, see `Connection::rtt`.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.conn.rtt()
+    }
+
+    /// Returns an estimate of the current time on the server, see `Connection::remote_time`.
+    pub fn server_time(&self) -> Option<SystemTime> {
+        self.conn.remote_time()
     }
 
     /// Update the connection. Processes any messages received from the server.
@@ -128,8 +674,15 @@ impl<T> Client<T> where
 
             let packet = packet.unwrap();
 
-            let mut node = self.context.lock().unwrap().get(packet.node).unwrap().as_box();
+            // unreliable updates may arrive before the node they are for
+            let node = self.context.lock().unwrap().get(packet.node);
+            if node.is_none() && packet.is_unreliable() {
+                continue;
+            }
+
+            let mut node = node.unwrap().as_box();
             let mut de = Deserializer::with_current_node(Cursor::new(packet.data), packet.node);
+            de.set_limits(self.conn.config().limits);
             de.attach_context(self.context.clone());
             
             node.recv_update(de);
@@ -0,0 +1,599 @@
+use super::*;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering as AtomicOrdering};
+use std::io::{self, ErrorKind};
+use std::net::{self, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian};
+
+/// Size of the `node`, `magic`, `flags` and data length fields that precede the data of a `Packet`.
+const PACKET_HEADER: usize = 13;
+
+/// Largest unreliable `Packet` that `UdpTransport` sends as a single datagram, header included.
+/// A `Connection` splits larger unreliable messages into fragments that fit. 
+/// Unreliable `Packet`s that are still too large are sent through the reliable transport instead.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// Moves `Packet`s between the two ends of a `Connection`.
+/// A `Connection` handles everything that is done to the contents of `Packet`s, like heartbeats,
+///  compression and encryption, so a `Transport` only needs to deliver `Packet`s in order.
+/// Use `Connection::from_transport` or `Server::add_transport_client` to plug in a custom transport.
+pub trait Transport {
+    /// Sends a single `Packet`. The `Packet` may be buffered until the next `poll`.
+    fn send(&mut self, packet: Packet) -> Result<(), Error>;
+
+    /// Sends a single `Packet` that may be delivered late or not at all, see `Packet::is_unreliable`.
+    /// Transports without an unreliable channel send it like any other `Packet`.
+    fn send_unreliable(&mut self, packet: Packet) -> Result<(), Error> {
+        self.send(packet)
+    }
+
+    /// Flushes buffered `Packet`s and appends all received `Packet`s to `packets`, without blocking.
+    fn poll(&mut self, packets: &mut Vec<Packet>) -> Result<(), Error>;
+
+    /// Blocks for at most `timeout`, or until a `Packet` can be returned by `poll`.
+    fn wait(&mut self, timeout: Duration) -> Result<(), Error> {
+        thread::sleep(timeout.min(Duration::from_millis(1)));
+        Ok(())
+    }
+
+    /// Stops the transport. Called after the close frame has been sent.
+    fn close(&mut self);
+
+    /// Returns an error once the transport can not be used anymore.
+    fn status(&mut self) -> Result<(), Error>;
+
+    /// Applies the settings of the `Connection` that are relevant to the transport,
+    ///  like `Limits::max_packet_size`.
+    fn configure(&mut self, _config: &Config) { }
+
+    /// The largest amount of data a single `Packet` should carry, if the transport has a limit.
+    /// The `Connection` splits larger messages into fragments, see `Config::fragment_size`.
+    fn fragment_size(&self, _unreliable: bool) -> Option<usize> {
+        None
+    }
+
+    /// The number of bytes that were sent, but have not been handed to the network yet.
+    /// The `Connection` applies `Config::send_queue` based on this.
+    fn queued(&self) -> usize {
+        0
+    }
+}
+
+/// A `Transport` over a blocking `Write` and `Read` pair, like a `TcpStream`.
+/// Dedicated threads write to the `Write` and read from the `Read` implementation, 
+///  so sending never blocks on the network. See `Connection::new`.
+pub struct StreamTransport {
+    w: Option<Sender<Vec<u8>>>,
+    r: Receiver<Packet>,
+    pending: Option<Packet>,
+    running: Arc<AtomicBool>,
+    err: Arc<Mutex<Option<Error>>>,
+    queued: Arc<AtomicUsize>,
+    max_packet_size: Arc<AtomicUsize>,
+}
+
+/// A `Transport` over a non-blocking `Write` and `Read` pair, see `Connection::polled`.
+pub struct PolledTransport {
+    w: Box<Write>,
+    r: Box<Read>,
+    out: Vec<u8>,
+    buf: Vec<u8>,
+    max_packet_size: usize,
+}
+
+/// A `Transport` over in-process channels, see `Connection::pair`.
+pub struct ChannelTransport {
+    sender: Option<Sender<Packet>>,
+    receiver: Receiver<Packet>,
+    pending: Option<Packet>,
+}
+
+/// A `Transport` that adds an unreliable channel over UDP to another, reliable, `Transport`.
+/// `Packet`s sent with `Connection::send_unreliable` go out as single datagrams,
+///  everything else goes through the reliable transport.
+pub struct UdpTransport<T: Transport> {
+    reliable: T,
+    socket: UdpSocket,
+    buf: Vec<u8>,
+    max_packet_size: usize,
+}
+
+/// A stream that can be shut down while another thread is blocked reading from it, like a `TcpStream`.
+/// `StreamTransport` shuts down it's writer once everything has been written after `close`, 
+///  which ends the read of the reader thread so it can be joined.
+trait Shutdown {
+    /// Shuts down both directions of the stream.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Shutdown for TcpStream {
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, net::Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Shutdown for UnixStream {
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, net::Shutdown::Both)
+    }
+}
+
+/// A stream that can be switched to non-blocking mode, like a `TcpStream`.
+/// `PolledTransport` switches both of it's halves to non-blocking mode, so polling never blocks.
+pub trait NonBlocking {
+    /// Moves the stream into or out of non-blocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl NonBlocking for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl NonBlocking for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Returns a handle to shut down `w` with, if it's a `TcpStream` or a `UnixStream`.
+fn shutdown_handle<W: Any>(w: &W) -> Option<Box<Shutdown + Send>> {
+    let w = w as &Any;
+    if let Some(stream) = w.downcast_ref::<TcpStream>() {
+        return stream.try_clone().ok().map(|s| Box::new(s) as Box<Shutdown + Send>);
+    }
+    #[cfg(unix)]
+    {
+        if let Some(stream) = w.downcast_ref::<UnixStream>() {
+            return stream.try_clone().ok().map(|s| Box::new(s) as Box<Shutdown + Send>);
+        }
+    }
+    None
+}
+
+impl StreamTransport {
+    /// When `w` is a `TcpStream` or a `UnixStream`, it is shut down once everything has been 
+    ///  written after `close`, which stops the reader thread. Otherwise the reader thread stops 
+    ///  when the remote end closes the stream.
+    pub fn new<W: 'static + Write + Send, R: 'static + Read + Send>(w: W, r: R) -> Self {
+        let shutdown = shutdown_handle(&w);
+
+        let (sender, receiver) = channel();
+        let (writer, outgoing) = channel::<Vec<u8>>();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let err = Arc::new(Mutex::new(None));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let max_packet_size = Arc::new(AtomicUsize::new(Limits::default().max_packet_size));
+
+        let result = StreamTransport {
+            w: Some(writer),
+            r: receiver,
+            pending: None,
+            running: running.clone(),
+            err: err.clone(),
+            queued: queued.clone(),
+            max_packet_size: max_packet_size.clone(),
+        };
+
+        let reader_running = running.clone();
+        let reader_err = err.clone();
+        let reader = thread::spawn(move || {
+            let mut r = r;
+            while reader_running.load(AtomicOrdering::Relaxed) {
+                let result = read_packet(&mut r, max_packet_size.load(AtomicOrdering::Relaxed));
+                if result.is_err() {
+                    reader_err.lock().unwrap().get_or_insert(result.err().unwrap());
+                    break;
+                }
+                if sender.send(result.unwrap()).is_err() {
+                    reader_err.lock().unwrap().get_or_insert(Error::Custom("Channel Error".into()));
+                    break;
+                }
+            }
+
+            reader_running.swap(false, AtomicOrdering::Relaxed);
+        });
+
+        // the writer thread keeps going until the channel is hung up and everything is written, 
+        //  then it shuts down the stream to wake up the reader thread
+        thread::spawn(move || {
+            let mut w = w;
+            for data in outgoing {
+                let result = w.write_all(&data).and_then(|_| w.flush());
+                if result.is_err() {
+                    err.lock().unwrap().get_or_insert(result.err().unwrap().into());
+                    running.swap(false, AtomicOrdering::Relaxed);
+                    // nothing that is still queued will be written
+                    queued.store(0, AtomicOrdering::Relaxed);
+                    break;
+                }
+                queued.fetch_sub(data.len(), AtomicOrdering::Relaxed);
+            }
+
+            running.swap(false, AtomicOrdering::Relaxed);
+            drop(w);
+            if shutdown.is_some_and(|s| s.shutdown().is_ok()) {
+                reader.join().ok();
+            }
+        });
+
+        result
+    }
+
+    /// The error stored by the reader or writer thread, if there is one.
+    fn error(&mut self) -> Error {
+        self.err.lock().unwrap().take().unwrap_or_else(|| Error::Custom("Connection Closed".into()))
+    }
+}
+
+impl Transport for StreamTransport {
+    /// Hands the `Packet` to the writer thread.
+    fn send(&mut self, packet: Packet) -> Result<(), Error> {
+        let mut data = encode_header(&packet).to_vec();
+        data.extend_from_slice(&packet.data);
+
+        let len = data.len();
+        self.queued.fetch_add(len, AtomicOrdering::Relaxed);
+        match self.w {
+            Some(ref w) if w.send(data).is_ok() => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn poll(&mut self, packets: &mut Vec<Packet>) -> Result<(), Error> {
+        packets.extend(self.pending.take());
+        loop {
+            match self.r.try_recv() {
+                Ok(packet) => packets.push(packet),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(self.error()),
+            }
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.pending.is_none() {
+            match self.r.recv_timeout(timeout) {
+                Ok(packet) => self.pending = Some(packet),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return Err(self.error()),
+            }
+        }
+        Ok(())
+    }
+
+    /// The writer thread stops once it has written everything that was sent, 
+    ///  which includes the close frame. It then shuts down the stream, which stops the reader thread.
+    fn close(&mut self) {
+        self.running.swap(false, AtomicOrdering::Relaxed);
+        self.w = None;
+    }
+
+    fn status(&mut self) -> Result<(), Error> {
+        if self.w.is_some() && !self.running.load(AtomicOrdering::Relaxed) {
+            Err(self.error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.max_packet_size.store(config.limits.max_packet_size, AtomicOrdering::Relaxed);
+    }
+
+    fn queued(&self) -> usize {
+        self.queued.load(AtomicOrdering::Relaxed)
+    }
+}
+
+impl Drop for StreamTransport {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl PolledTransport {
+    /// Switches `w` and `r` to non-blocking mode. Each `poll` then writes what the stream 
+    ///  accepts and reads what is available, without waiting for more.
+    pub fn new<W, R>(w: W, r: R) -> Result<Self, Error> where
+        W: 'static + Write + NonBlocking,
+        R: 'static + Read + NonBlocking,
+    {
+        w.set_nonblocking(true)?;
+        r.set_nonblocking(true)?;
+
+        Ok(PolledTransport {
+            w: Box::new(w),
+            r: Box::new(r),
+            out: Vec::new(),
+            buf: Vec::new(),
+            max_packet_size: Limits::default().max_packet_size,
+        })
+    }
+
+    /// Writes as much of the buffered output as the writer accepts right now.
+    fn flush(&mut self) -> Result<(), Error> {
+        while !self.out.is_empty() {
+            match self.w.write(&self.out) {
+                Ok(0) => return Err(Error::Custom("Connection Closed".into())),
+                Ok(n) => { self.out.drain(..n); },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for PolledTransport {
+    fn send(&mut self, packet: Packet) -> Result<(), Error> {
+        self.out.extend_from_slice(&encode_header(&packet));
+        self.out.extend_from_slice(&packet.data);
+
+        self.flush()
+    }
+
+    fn poll(&mut self, packets: &mut Vec<Packet>) -> Result<(), Error> {
+        self.flush()?;
+
+        let mut chunk = [0u8; 4096];
+        let result = loop {
+            match self.r.read(&mut chunk) {
+                Ok(0) => break Err(Error::Custom("Connection Closed".into())),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e.into()),
+            }
+        };
+
+        // split off all packets that have been received completely
+        while self.buf.len() >= PACKET_HEADER {
+            let (node, flags, len) = parse_header(&self.buf[..PACKET_HEADER], self.max_packet_size)?;
+            if self.buf.len() < PACKET_HEADER + len {
+                break;
+            }
+
+            let data = self.buf[PACKET_HEADER..PACKET_HEADER + len].to_vec();
+            packets.push(Packet::new(node, flags, data));
+
+            self.buf.drain(..PACKET_HEADER + len);
+        }
+
+        result
+    }
+
+    /// Makes a last attempt to write the buffered output, which includes the close frame.
+    fn close(&mut self) {
+        self.flush().ok();
+    }
+
+    fn status(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.max_packet_size = config.limits.max_packet_size;
+    }
+
+    fn queued(&self) -> usize {
+        self.out.len()
+    }
+}
+
+impl<T: Transport> UdpTransport<T> {
+    /// Wraps the `reliable` transport. The `socket` should already be connected to the socket 
+    ///  of the remote end, which is set up the same way. It's switched to non-blocking mode.
+    pub fn new(reliable: T, socket: UdpSocket) -> Result<Self, Error> {
+        socket.set_nonblocking(true)?;
+
+        Ok(UdpTransport {
+            reliable,
+            socket,
+            buf: vec![0u8; 65536],
+            max_packet_size: Limits::default().max_packet_size,
+        })
+    }
+
+    /// Decodes a received datagram. Anything that isn't a valid unreliable `Packet` is ignored.
+    fn parse(&self, datagram: &[u8]) -> Option<Packet> {
+        if datagram.len() < PACKET_HEADER {
+            return None;
+        }
+
+        let (node, flags, len) = parse_header(&datagram[..PACKET_HEADER], self.max_packet_size).ok()?;
+        let packet = Packet::new(node, flags, datagram[PACKET_HEADER..].to_vec());
+        if len == packet.data.len() && packet.is_unreliable() {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Transport> Transport for UdpTransport<T> {
+    fn send(&mut self, packet: Packet) -> Result<(), Error> {
+        self.reliable.send(packet)
+    }
+
+    fn send_unreliable(&mut self, packet: Packet) -> Result<(), Error> {
+        if PACKET_HEADER + packet.data.len() > MAX_DATAGRAM_SIZE {
+            return self.reliable.send(packet);
+        }
+
+        let mut datagram = encode_header(&packet).to_vec();
+        datagram.extend_from_slice(&packet.data);
+
+        // a datagram that can't be sent right now is lost, like any other unreliable packet
+        self.socket.send(&datagram).ok();
+        Ok(())
+    }
+
+    fn poll(&mut self, packets: &mut Vec<Packet>) -> Result<(), Error> {
+        let result = self.reliable.poll(packets);
+
+        loop {
+            let received = match self.socket.recv(&mut self.buf) {
+                Ok(n) => self.parse(&self.buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                // nothing left, or an error caused by an earlier datagram like ConnectionRefused
+                Err(_) => break,
+            };
+            packets.extend(received);
+        }
+
+        result
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.reliable.wait(timeout)
+    }
+
+    fn close(&mut self) {
+        self.reliable.close();
+    }
+
+    fn status(&mut self) -> Result<(), Error> {
+        self.reliable.status()
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.max_packet_size = config.limits.max_packet_size;
+        self.reliable.configure(config);
+    }
+
+    fn fragment_size(&self, unreliable: bool) -> Option<usize> {
+        if unreliable {
+            Some(MAX_DATAGRAM_SIZE - PACKET_HEADER)
+        } else {
+            self.reliable.fragment_size(false)
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.reliable.queued()
+    }
+}
+
+impl ChannelTransport {
+    /// Initialize two `ChannelTransport`s that are connected to each other.
+    pub fn pair() -> (Self, Self) {
+        let (a_sender, a_receiver) = channel();
+        let (b_sender, b_receiver) = channel();
+
+        (Self::new(a_sender, b_receiver), Self::new(b_sender, a_receiver))
+    }
+
+    fn new(sender: Sender<Packet>, receiver: Receiver<Packet>) -> Self {
+        ChannelTransport {
+            sender: Some(sender),
+            receiver,
+            pending: None,
+        }
+    }
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, packet: Packet) -> Result<(), Error> {
+        self.sender.as_ref()
+            .ok_or_else(|| Error::Custom("Connection Closed".into()))?
+            .send(packet)
+            .map_err(|_| Error::Custom("Channel Error".into()))
+    }
+
+    fn poll(&mut self, packets: &mut Vec<Packet>) -> Result<(), Error> {
+        packets.extend(self.pending.take());
+        loop {
+            match self.receiver.try_recv() {
+                Ok(packet) => packets.push(packet),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(Error::Custom("Connection Closed".into()));
+                },
+            }
+        }
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.pending.is_none() {
+            match self.receiver.recv_timeout(timeout) {
+                Ok(packet) => self.pending = Some(packet),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Custom("Connection Closed".into()));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Hangs up the channel, so the remote end sees the `Connection` closing.
+    fn close(&mut self) {
+        self.sender = None;
+    }
+
+    fn status(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Reads a single `Packet` from a blocking reader.
+/// Fails before allocating if the data is larger than `max_packet_size`.
+fn read_packet<R: Read>(r: &mut R, max_packet_size: usize) -> Result<Packet, Error> {
+    let mut header = [0u8; PACKET_HEADER];
+    r.read_exact(&mut header)?;
+    let (node, flags, len) = parse_header(&header, max_packet_size)?;
+
+    let mut data = vec![0u8; len];
+    r.read_exact(&mut data)?;
+
+    Ok(Packet::new(node, flags, data))
+}
+
+/// Encodes the header that precedes the data of a `Packet`.
+fn encode_header(packet: &Packet) -> [u8; PACKET_HEADER] {
+    let mut header = [0u8; PACKET_HEADER];
+    BigEndian::write_u32(&mut header[0..4], packet.node);
+    BigEndian::write_u32(&mut header[4..8], PACKET_MAGIC);
+    header[8] = packet.flags();
+    BigEndian::write_u32(&mut header[9..13], packet.data.len() as u32);
+    header
+}
+
+/// Validates the header that precedes the data of a `Packet`. Returns the node, flags and data length.
+fn parse_header(header: &[u8], max_packet_size: usize) -> Result<(u32, u8, usize), Error> {
+    if BigEndian::read_u32(&header[4..8]) != PACKET_MAGIC {
+        return Err(Error::Custom("Corrupt Packet".into()));
+    }
+
+    let len = BigEndian::read_u32(&header[9..13]) as usize;
+    Limits::check("packet size", len, max_packet_size)?;
+
+    Ok((BigEndian::read_u32(&header[0..4]), header[8], len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn refuses_oversized_packets_before_reading_them() {
+        let mut header = encode_header(&Packet::new(1, 0, vec![]));
+        BigEndian::write_u32(&mut header[9..13], u32::MAX);
+        let mut r = Cursor::new(header.to_vec());
+
+        let result = read_packet(&mut r, Limits::default().max_packet_size);
+        assert!(matches!(result, Err(Error::LimitExceeded("packet size", len)) if len == u32::MAX as usize));
+        assert_eq!(r.position(), PACKET_HEADER as u64);
+    }
+}
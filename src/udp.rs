@@ -0,0 +1,356 @@
+use super::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+use byteorder::{ByteOrder, BigEndian};
+
+/// Datagram containing a reliable `Packet`, preceded by it's sequence number.
+const KIND_DATA: u8 = 0;
+/// Datagram acknowledging reliable `Packet`s: the next expected sequence number and
+///  the sequence number that was just received.
+const KIND_ACK: u8 = 1;
+/// Datagram containing an unreliable `Packet`.
+const KIND_UNRELIABLE: u8 = 2;
+
+/// Largest datagram that is sent or received.
+const MAX_DATAGRAM: usize = 65507;
+
+/// Size of the kind, sequence number and encoded `Packet` fields that precede the data of a `Packet`.
+const DATAGRAM_HEADER: usize = 22;
+
+/// Number of reliable `Packet`s, starting at the oldest one that is missing, that can be in flight. 
+/// The receiving end drops datagrams beyond it without an ack, the sending end holds them back.
+const WINDOW: u64 = 1024;
+
+/// Settings for the retransmission of a `ReliableUdpTransport`.
+#[derive(Clone, Debug)]
+pub struct Retransmission {
+    /// A reliable `Packet` is sent again when it hasn't been acknowledged for this long.
+    pub interval: Duration,
+    /// The transport fails with `DisconnectReason::Timeout` when a `Packet` has been sent this
+    ///  many times without being acknowledged.
+    pub max_attempts: u32,
+}
+
+impl Default for Retransmission {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(100),
+            max_attempts: 50,
+        }
+    }
+}
+
+struct Unacked {
+    sequence: u64,
+    datagram: Vec<u8>,
+    sent: Instant,
+    /// Zero while the datagram is held back because it's outside of the `WINDOW`.
+    attempts: u32,
+}
+
+/// A `Transport` that runs all traffic of a `Connection` over a single UDP socket.
+/// Reliable `Packet`s get a sequence number and are sent again until they are acknowledged.
+/// The receiving end drops duplicates and delivers them in order. At most 1024 `Packet`s are 
+///  in flight, and out-of-order data is held back up to `Limits::max_buffered`.
+/// Unreliable `Packet`s are sent as plain datagrams, see `Connection::send_unreliable`.
+/// The `Connection` splits messages into fragments so every datagram stays below `MAX_DATAGRAM_SIZE`.
+pub struct ReliableUdpTransport {
+    socket: UdpSocket,
+    retransmission: Retransmission,
+    limits: Limits,
+    buf: Vec<u8>,
+    next_sequence: u64,
+    unacked: VecDeque<Unacked>,
+    expected: u64,
+    received: BTreeMap<u64, Packet>,
+    /// Bytes of data in `received`, see `Limits::max_buffered`.
+    buffered: usize,
+    failed: bool,
+}
+
+impl ReliableUdpTransport {
+    /// Initialize a new `ReliableUdpTransport`. The `socket` should already be connected to the
+    ///  socket of the remote end, which is set up the same way. It's switched to non-blocking mode.
+    pub fn new(socket: UdpSocket) -> Result<Self, Error> {
+        Self::with_retransmission(socket, Retransmission::default())
+    }
+
+    /// Like `ReliableUdpTransport::new`, but with custom retransmission settings.
+    pub fn with_retransmission(socket: UdpSocket, retransmission: Retransmission) -> Result<Self, Error> {
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            retransmission,
+            limits: Limits::default(),
+            buf: vec![0u8; MAX_DATAGRAM],
+            next_sequence: 0,
+            unacked: VecDeque::new(),
+            expected: 0,
+            received: BTreeMap::new(),
+            buffered: 0,
+            failed: false,
+        })
+    }
+
+    /// Sends a datagram. A datagram that can't be sent right now is treated as lost.
+    fn send_datagram(&self, datagram: &[u8]) {
+        self.socket.send(datagram).ok();
+    }
+
+    /// Sends all reliable `Packet`s within the `WINDOW` that have not been sent yet, 
+    ///  or that have not been acknowledged in time.
+    fn retransmit(&mut self) {
+        let end = match self.unacked.front() {
+            Some(unacked) => unacked.sequence + WINDOW,
+            None => return,
+        };
+
+        for unacked in self.unacked.iter_mut().take_while(|u| u.sequence < end) {
+            if unacked.attempts == 0 || unacked.sent.elapsed() >= self.retransmission.interval {
+                self.socket.send(&unacked.datagram).ok();
+                unacked.sent = Instant::now();
+                unacked.attempts += 1;
+                if unacked.attempts > self.retransmission.max_attempts {
+                    self.failed = true;
+                }
+            }
+        }
+    }
+
+    /// Processes a received datagram. Datagrams that can't be decoded are ignored.
+    fn receive(&mut self, len: usize, packets: &mut Vec<Packet>) {
+        if len < 1 {
+            return;
+        }
+
+        match self.buf[0] {
+            KIND_DATA if len >= 9 => {
+                let sequence = BigEndian::read_u64(&self.buf[1..9]);
+
+                // too far ahead, the sender retransmits it once the gap before it is filled
+                if sequence >= self.expected.saturating_add(WINDOW) {
+                    return;
+                }
+
+                let packet = decode(&self.buf[9..len], &self.limits);
+                let new = sequence >= self.expected && !self.received.contains_key(&sequence);
+
+                if let Some(packet) = packet.filter(|_| new) {
+                    // the expected packet is always taken, so a full buffer can still drain
+                    let buffered = self.buffered + packet.data.len();
+                    if sequence > self.expected && buffered > self.limits.max_buffered {
+                        return;
+                    }
+
+                    self.buffered = buffered;
+                    self.received.insert(sequence, packet);
+                    while let Some(packet) = self.received.remove(&self.expected) {
+                        self.buffered -= packet.data.len();
+                        packets.push(packet);
+                        self.expected += 1;
+                    }
+                }
+
+                // duplicates are acknowledged again, since the previous ack could have been lost
+                let mut ack = [0u8; 17];
+                ack[0] = KIND_ACK;
+                BigEndian::write_u64(&mut ack[1..9], self.expected);
+                BigEndian::write_u64(&mut ack[9..17], sequence);
+                self.send_datagram(&ack);
+            },
+            KIND_ACK if len >= 17 => {
+                let expected = BigEndian::read_u64(&self.buf[1..9]);
+                let sequence = BigEndian::read_u64(&self.buf[9..17]);
+                self.unacked.retain(|u| u.sequence >= expected && u.sequence != sequence);
+            },
+            KIND_UNRELIABLE => {
+                let packet = decode(&self.buf[1..len], &self.limits);
+                packets.extend(packet.filter(|p| p.is_unreliable()));
+            },
+            _ => (),
+        }
+    }
+}
+
+impl Transport for ReliableUdpTransport {
+    fn send(&mut self, packet: Packet) -> Result<(), Error> {
+        let mut datagram = vec![KIND_DATA; 9];
+        BigEndian::write_u64(&mut datagram[1..9], self.next_sequence);
+        encode(packet, &mut datagram)?;
+
+        let in_window = self.unacked.front().is_none_or(|u| self.next_sequence < u.sequence + WINDOW);
+        if in_window {
+            self.send_datagram(&datagram);
+        }
+        self.unacked.push_back(Unacked {
+            sequence: self.next_sequence,
+            datagram,
+            sent: Instant::now(),
+            attempts: if in_window { 1 } else { 0 },
+        });
+        self.next_sequence += 1;
+
+        Ok(())
+    }
+
+    fn send_unreliable(&mut self, packet: Packet) -> Result<(), Error> {
+        let mut datagram = vec![KIND_UNRELIABLE];
+        encode(packet, &mut datagram)?;
+
+        self.send_datagram(&datagram);
+        Ok(())
+    }
+
+    fn poll(&mut self, packets: &mut Vec<Packet>) -> Result<(), Error> {
+        loop {
+            match self.socket.recv(&mut self.buf) {
+                Ok(len) => self.receive(len, packets),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                // nothing left, or an error caused by an earlier datagram like ConnectionRefused
+                Err(_) => break,
+            }
+        }
+
+        self.retransmit();
+        self.status()
+    }
+
+    /// Makes a last attempt to deliver everything that has not been acknowledged,
+    ///  which includes the close frame.
+    fn close(&mut self) {
+        for unacked in self.unacked.iter() {
+            self.socket.send(&unacked.datagram).ok();
+        }
+        self.unacked.clear();
+    }
+
+    fn status(&mut self) -> Result<(), Error> {
+        if self.failed {
+            Err(Error::Disconnected(DisconnectReason::Timeout))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn configure(&mut self, config: &Config) {
+        self.limits = config.limits.clone();
+    }
+
+    fn fragment_size(&self, _unreliable: bool) -> Option<usize> {
+        Some(MAX_DATAGRAM_SIZE - DATAGRAM_HEADER)
+    }
+
+    /// Everything that has not been acknowledged yet counts as queued.
+    fn queued(&self) -> usize {
+        self.unacked.iter().map(|u| u.datagram.len()).sum()
+    }
+}
+
+/// Appends `packet` to `datagram`, failing if the result doesn't fit in a single datagram.
+fn encode(mut packet: Packet, datagram: &mut Vec<u8>) -> Result<(), Error> {
+    packet.reflect(&mut Serializer::new(&mut *datagram))?;
+    Limits::check("datagram size", datagram.len(), MAX_DATAGRAM)
+}
+
+/// Decodes a `Packet` from a datagram, returning `None` if it's invalid.
+fn decode(data: &[u8], limits: &Limits) -> Option<Packet> {
+    let mut packet = Packet::default();
+    let mut de = Deserializer::new(data);
+    de.set_limits(limits.clone());
+    de.limits.max_collection_len = limits.max_packet_size;
+
+    packet.reflect(&mut de).ok()?;
+    packet.check().ok()?;
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `ReliableUdpTransport` and a plain socket that plays the remote end.
+    fn peer() -> (ReliableUdpTransport, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        (ReliableUdpTransport::new(a).unwrap(), b)
+    }
+
+    fn data(sequence: u64, node: u32, len: usize) -> Vec<u8> {
+        let mut datagram = vec![KIND_DATA; 9];
+        BigEndian::write_u64(&mut datagram[1..9], sequence);
+        encode(Packet::new(node, 0, vec![0; len]), &mut datagram).unwrap();
+        datagram
+    }
+
+    fn poll(transport: &mut ReliableUdpTransport) -> Vec<u32> {
+        let mut packets = Vec::new();
+        for _ in 0..10 {
+            transport.poll(&mut packets).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        packets.iter().map(|p| p.node).collect()
+    }
+
+    fn acked(socket: &UdpSocket) -> bool {
+        let mut buf = [0u8; 64];
+        socket.recv(&mut buf).map(|_| buf[0] == KIND_ACK).unwrap_or(false)
+    }
+
+    #[test]
+    fn reorders_and_drops_duplicates() {
+        let (mut transport, remote) = peer();
+        for &sequence in &[2, 0, 2, 1, 0] {
+            remote.send(&data(sequence, sequence as u32 + 1, 4)).unwrap();
+        }
+        assert_eq!(poll(&mut transport), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn drops_datagrams_beyond_the_window() {
+        let (mut transport, remote) = peer();
+        remote.send(&data(WINDOW, 1, 4)).unwrap();
+        assert!(poll(&mut transport).is_empty());
+        assert!(!acked(&remote));
+        assert!(transport.received.is_empty());
+
+        remote.send(&data(0, 2, 4)).unwrap();
+        assert_eq!(poll(&mut transport), vec![2]);
+        assert!(acked(&remote));
+    }
+
+    #[test]
+    fn holds_back_packets_beyond_the_window() {
+        let (mut transport, _remote) = peer();
+        for node in 0..WINDOW as u32 + 10 {
+            transport.send(Packet::new(node, 0, vec![])).unwrap();
+        }
+
+        let held_back = transport.unacked.iter().filter(|u| u.attempts == 0);
+        assert_eq!(held_back.map(|u| u.sequence).min(), Some(WINDOW));
+        assert_eq!(transport.unacked.iter().filter(|u| u.attempts == 0).count(), 10);
+    }
+
+    #[test]
+    fn limits_buffered_data() {
+        let (mut transport, remote) = peer();
+        let mut config = Config::default();
+        config.limits.max_buffered = 100;
+        transport.configure(&config);
+
+        remote.send(&data(1, 2, 80)).unwrap();
+        remote.send(&data(2, 3, 80)).unwrap();
+        assert!(poll(&mut transport).is_empty());
+        assert_eq!(transport.buffered, 80);
+
+        // the expected packet is taken even though the buffer is full
+        remote.send(&data(0, 1, 80)).unwrap();
+        assert_eq!(poll(&mut transport), vec![1, 2]);
+        assert_eq!(transport.buffered, 0);
+    }
+}
@@ -3,25 +3,81 @@ use std::hash::Hash;
 use std::collections::HashMap;
 use node::NodeContext;
 
+/// Upper bounds on what a `Deserializer` accepts from the wire. 
+/// Length prefixes are checked against these before anything is allocated, 
+///  a violation fails with `Error::LimitExceeded`.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Maximum size in bytes of the data of a `Packet`, enforced by `Connection`.
+    pub max_packet_size: usize,
+    /// Maximum number of elements in a `Vec` or `HashMap`.
+    pub max_collection_len: usize,
+    /// Maximum length in bytes of a `String`.
+    pub max_string_len: usize,
+    /// Maximum nesting depth of visited values.
+    pub max_depth: usize,
+    /// Maximum number of bytes a `Transport` holds back while waiting for missing data, 
+    ///  like the out-of-order datagrams of a `ReliableUdpTransport`.
+    pub max_buffered: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_packet_size: 16 << 20,
+            max_collection_len: 1 << 20,
+            max_string_len: 1 << 20,
+            max_depth: 128,
+            max_buffered: 16 << 20,
+        }
+    }
+}
+
+impl Limits {
+    /// Returns `Error::LimitExceeded` if `value` is larger than `limit`.
+    pub fn check(name: &'static str, value: usize, limit: usize) -> Result<(), Error> {
+        if value > limit {
+            Err(Error::LimitExceeded(name, value))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub struct Deserializer<R: Read> {
     pub reader: R,
     pub current_node: Option<u32>,
+    pub limits: Limits,
+    depth: usize,
     context: Option<Box<Any>>,
 }
 
 impl<R: Read> Deserializer<R> {
     pub fn new(reader: R) -> Self {
         Self {
-            reader, current_node: None, context: None,
+            reader, current_node: None, limits: Limits::default(), depth: 0, context: None,
         }
     }
 
     pub fn with_current_node(reader: R, current_node: u32) -> Self {
         Self {
-            reader, current_node: Some(current_node), context: None
+            reader, current_node: Some(current_node), limits: Limits::default(), depth: 0, context: None
         }
     }
 
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Reflects `val` one level deeper, failing if that exceeds `Limits::max_depth`.
+    fn nested<T: Reflect<Self>>(&mut self, val: &mut T) -> Result<(), Error> {
+        self.depth += 1;
+        let result = Limits::check("depth", self.depth, self.limits.max_depth)
+            .and_then(|_| val.reflect(self));
+        self.depth -= 1;
+        result
+    }
+
     pub fn attach_context<G: Tag>(&mut self, context: Arc<Mutex<NodeContext<G>>>) {
         self.context = Some(Box::new(context));
     }
@@ -36,7 +92,7 @@ impl<R: Read> Deserializer<R> {
 
 impl<R: Read> Visitor for Deserializer<R> {
     fn visit<T: Reflect<Deserializer<R>>>(&mut self, _name: &str, val: &mut T) -> Result<(), Error> {
-        Ok(val.reflect(self)?)
+        self.nested(val)
     }
 }
 
@@ -62,7 +118,22 @@ encodable!{ u64 }
 encodable!{ i64 }
 encodable!{ f64 }
 encodable!{ bool }
-encodable!{ String }
+
+impl<R: Read> Reflect<Deserializer<R>> for String {
+    fn reflect(&mut self, visit: &mut Deserializer<R>) -> Result<(), Error> {
+        let mut len = 0u32;
+        len.reflect(visit)?;
+        Limits::check("string length", len as usize, visit.limits.max_string_len)?;
+
+        let mut content = Vec::with_capacity(len as usize);
+        (&mut visit.reader).take(len as u64).read_to_end(&mut content)?;
+        if content.len() < len as usize {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        *self = String::from_utf8(content)?;
+        Ok(())
+    }
+}
 
 impl<R, T> Reflect<Deserializer<R>> for Vec<T> where 
     R: Read,
@@ -71,10 +142,11 @@ impl<R, T> Reflect<Deserializer<R>> for Vec<T> where
     fn reflect(&mut self, visit: &mut Deserializer<R>) -> Result<(), Error> {
         let mut len = 0u32;
         len.reflect(visit)?;
+        Limits::check("collection length", len as usize, visit.limits.max_collection_len)?;
         self.clear();
         for _ in 0..len {
             self.push(T::default());
-            self.last_mut().unwrap().reflect(visit)?;
+            visit.nested(self.last_mut().unwrap())?;
         }
         Ok(())
     }
@@ -88,16 +160,46 @@ impl<R, K, V> Reflect<Deserializer<R>> for HashMap<K, V> where
     fn reflect(&mut self, visit: &mut Deserializer<R>) -> Result<(), Error> {
         let mut len = 0u32;
         len.reflect(visit)?;
+        Limits::check("collection length", len as usize, visit.limits.max_collection_len)?;
         self.clear();
         for _ in 0..len {
             let mut k = K::default();
             let mut v = V::default();
 
-            k.reflect(visit)?;
-            v.reflect(visit)?;
+            visit.nested(&mut k)?;
+            visit.nested(&mut v)?;
 
             self.insert(k, v);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A length prefix of `u32::MAX` followed by a few bytes.
+    const HOSTILE: &[u8] = &[0xff, 0xff, 0xff, 0xff, 1, 2, 3];
+
+    fn exceeded<T: Default + Reflect<Deserializer<&'static [u8]>>>() -> bool {
+        let mut de = Deserializer::new(HOSTILE);
+        let mut val = T::default();
+        matches!(val.reflect(&mut de), Err(Error::LimitExceeded(_, len)) if len == u32::MAX as usize)
+    }
+
+    #[test]
+    fn refuses_hostile_lengths() {
+        assert!(exceeded::<Vec<u64>>());
+        assert!(exceeded::<HashMap<u32, String>>());
+        assert!(exceeded::<String>());
+    }
+
+    #[test]
+    fn refuses_lengths_over_the_limit() {
+        let mut de = Deserializer::new(&[0, 0, 0, 3, b'a', b'b', b'c'][..]);
+        de.limits.max_string_len = 2;
+        let mut val = String::new();
+        assert!(matches!(val.reflect(&mut de), Err(Error::LimitExceeded("string length", 3))));
+    }
+}
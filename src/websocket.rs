@@ -0,0 +1,364 @@
+use super::*;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use byteorder::{ByteOrder, BigEndian};
+use sha1_smol::Sha1;
+
+/// Appended to the key of the client to compute the `Sec-WebSocket-Accept` header.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest HTTP request or response accepted during the handshake.
+const MAX_HANDSHAKE_SIZE: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Performs the HTTP upgrade handshake of RFC 6455, after which ggnet traffic is carried in
+///  binary WebSocket messages. The resulting `WebSocketWriter` and `WebSocketReader`
+///  can be handed to `Server::add_client` or `Connection::new` like the halves of a `TcpStream`.
+pub struct WebSocket;
+
+impl WebSocket {
+    /// Accepts a WebSocket connection from a client, like a browser.
+    /// `w` and `r` are the sending and receiving end of a freshly accepted socket.
+    /// Requests that are not a valid WebSocket upgrade are answered with `400 Bad Request`.
+    pub fn accept<W: Write, R: Read>(mut w: W, mut r: R) -> Result<(WebSocketWriter<W>, WebSocketReader<R, W>), Error> {
+        let request = read_head(&mut r)?;
+        let key = match validate_request(&request) {
+            Some(key) => key,
+            None => {
+                w.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+                return Err(Error::Custom("Invalid WebSocket handshake".into()));
+            },
+        };
+
+        write!(
+            w,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        )?;
+        w.flush()?;
+
+        Ok(split(w, r, false))
+    }
+
+    /// Connects to a WebSocket server. `w` and `r` are the sending and receiving end of a
+    ///  socket that is connected to the server, `host` and `path` are sent in the upgrade request.
+    pub fn connect<W: Write, R: Read>(mut w: W, mut r: R, host: &str, path: &str) -> Result<(WebSocketWriter<W>, WebSocketReader<R, W>), Error> {
+        let mut nonce = [0u8; 16];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|e| Error::Custom(format!("Unable to generate WebSocket key: {}", e)))?;
+        let key = base64(&nonce);
+
+        write!(
+            w,
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        )?;
+        w.flush()?;
+
+        let response = read_head(&mut r)?;
+        let status = response.lines().next().unwrap_or("");
+        let accepted = header(&response, "sec-websocket-accept");
+        if !status.starts_with("HTTP/1.1 101") || accepted != Some(accept_key(&key).as_str()) {
+            return Err(Error::Custom("Invalid WebSocket handshake".into()));
+        }
+
+        Ok(split(w, r, true))
+    }
+}
+
+/// Sends everything that is written as a binary WebSocket message.
+/// A close frame is sent when the `WebSocketWriter` is dropped or shut down.
+pub struct WebSocketWriter<W: Write> {
+    w: Arc<Mutex<W>>,
+    mask: bool,
+    closed: AtomicBool,
+}
+
+/// Reads the payload of the WebSocket messages sent by the remote end.
+/// Pings are answered automatically, a close frame is reported as the end of the stream.
+pub struct WebSocketReader<R: Read, W: Write> {
+    r: R,
+    /// Shared with the `WebSocketWriter`, to answer pings and close frames.
+    w: Arc<Mutex<W>>,
+    mask: bool,
+    /// Payload bytes left in the current frame.
+    remaining: u64,
+    key: Option<[u8; 4]>,
+    offset: usize,
+    closed: bool,
+}
+
+impl<W: Write> Write for WebSocketWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_frame(&mut *self.w.lock().unwrap(), OPCODE_BINARY, buf, self.mask)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.lock().unwrap().flush()
+    }
+}
+
+impl<W: Write> WebSocketWriter<W> {
+    /// Sends the close frame, unless it was sent before.
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let mut w = self.w.lock().unwrap();
+        // status code 1000, normal closure
+        write_frame(&mut *w, OPCODE_CLOSE, &[0x03, 0xE8], self.mask).ok();
+        w.flush().ok();
+    }
+}
+
+impl<W: Write> Drop for WebSocketWriter<W> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl<R: Read, W: Write> Read for WebSocketReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.closed || !self.next_frame()? {
+                return Ok(0);
+            }
+        }
+
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let n = self.r.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(key) = self.key {
+            for b in buf[..n].iter_mut() {
+                *b ^= key[self.offset % 4];
+                self.offset += 1;
+            }
+        }
+        self.remaining -= n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: Read, W: Write> WebSocketReader<R, W> {
+    /// Reads the header of the next frame. Control frames are handled right away.
+    /// Returns false once a close frame has been received.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        let mut header = [0u8; 2];
+        self.r.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0F;
+        let masked = header[1] & 0x80 != 0;
+
+        // clients must mask their frames and servers must not
+        if masked == self.mask || header[0] & 0x70 != 0 {
+            return Err(invalid_data("Invalid WebSocket frame"));
+        }
+
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut len = [0u8; 2];
+                self.r.read_exact(&mut len)?;
+                BigEndian::read_u16(&len) as u64
+            },
+            127 => {
+                let mut len = [0u8; 8];
+                self.r.read_exact(&mut len)?;
+                BigEndian::read_u64(&len)
+            },
+            len => len as u64,
+        };
+
+        self.key = if masked {
+            let mut key = [0u8; 4];
+            self.r.read_exact(&mut key)?;
+            Some(key)
+        } else {
+            None
+        };
+        self.offset = 0;
+
+        match opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                self.remaining = len;
+                Ok(true)
+            },
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if !fin || len > 125 {
+                    return Err(invalid_data("Invalid WebSocket control frame"));
+                }
+                let mut payload = vec![0u8; len as usize];
+                self.r.read_exact(&mut payload)?;
+                if let Some(key) = self.key {
+                    for (i, b) in payload.iter_mut().enumerate() {
+                        *b ^= key[i % 4];
+                    }
+                }
+
+                let mut w = self.w.lock().unwrap();
+                match opcode {
+                    OPCODE_PING => {
+                        write_frame(&mut *w, OPCODE_PONG, &payload, self.mask)?;
+                        w.flush()?;
+                        Ok(true)
+                    },
+                    OPCODE_CLOSE => {
+                        self.closed = true;
+                        Ok(false)
+                    },
+                    _ => Ok(true),
+                }
+            },
+            _ => Err(invalid_data("Unknown WebSocket opcode")),
+        }
+    }
+}
+
+/// Wraps both ends of a socket once the handshake is done.
+/// `mask` is true on the client side, which has to mask every frame it sends.
+fn split<W: Write, R: Read>(w: W, r: R, mask: bool) -> (WebSocketWriter<W>, WebSocketReader<R, W>) {
+    let w = Arc::new(Mutex::new(w));
+
+    let reader = WebSocketReader {
+        r,
+        w: w.clone(),
+        mask,
+        remaining: 0,
+        key: None,
+        offset: 0,
+        closed: false,
+    };
+
+    (WebSocketWriter { w, mask, closed: AtomicBool::new(false) }, reader)
+}
+
+/// Writes a single, final frame.
+fn write_frame<W: Write>(w: &mut W, opcode: u8, payload: &[u8], mask: bool) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+
+    let mask_bit = if mask { 0x80 } else { 0 };
+    if payload.len() < 126 {
+        frame.push(mask_bit | payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        frame.push(mask_bit | 126);
+        let mut len = [0u8; 2];
+        BigEndian::write_u16(&mut len, payload.len() as u16);
+        frame.extend_from_slice(&len);
+    } else {
+        frame.push(mask_bit | 127);
+        let mut len = [0u8; 8];
+        BigEndian::write_u64(&mut len, payload.len() as u64);
+        frame.extend_from_slice(&len);
+    }
+
+    if mask {
+        let mut key = [0u8; 4];
+        getrandom::getrandom(&mut key).map_err(|e| io::Error::other(e.to_string()))?;
+        frame.extend_from_slice(&key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+
+    w.write_all(&frame)
+}
+
+/// Reads an HTTP request or response up to and including the empty line after the headers.
+/// Reads a byte at a time, so nothing that follows the headers is consumed.
+fn read_head<R: Read>(r: &mut R) -> Result<String, Error> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        r.read_exact(&mut byte)?;
+        head.push(byte[0]);
+        Limits::check("handshake size", head.len(), MAX_HANDSHAKE_SIZE)?;
+    }
+    Ok(String::from_utf8(head)?)
+}
+
+/// Checks that `request` is a WebSocket upgrade and returns the `Sec-WebSocket-Key` if it is.
+fn validate_request(request: &str) -> Option<String> {
+    let upgrade = header(request, "upgrade")?;
+    let connection = header(request, "connection")?;
+    let version = header(request, "sec-websocket-version")?;
+
+    let valid = request.starts_with("GET ") &&
+        upgrade.eq_ignore_ascii_case("websocket") &&
+        connection.split(',').any(|c| c.trim().eq_ignore_ascii_case("upgrade")) &&
+        version == "13";
+
+    if valid {
+        header(request, "sec-websocket-key").map(String::from)
+    } else {
+        None
+    }
+}
+
+/// Returns the value of the header with `name`, which must be lowercase.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next()?.trim();
+        let value = parts.next()?.trim();
+        if key.eq_ignore_ascii_case(name) { Some(value) } else { None }
+    })
+}
+
+/// Computes the `Sec-WebSocket-Accept` header for the `Sec-WebSocket-Key` of a client.
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64(&sha1.digest().bytes())
+}
+
+/// Encodes `data` as standard base64 with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut result = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+}
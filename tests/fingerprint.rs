@@ -0,0 +1,67 @@
+#[macro_use] extern crate ggnet_derive;
+extern crate ggnet;
+
+use ggnet::*;
+
+mod before {
+    use ggnet::*;
+
+    #[derive(Reflect, Default)]
+    pub struct Struct { pub a: u8, pub bc: u8 }
+
+    #[derive(Reflect, Default)]
+    pub enum Split { #[default] A, B }
+
+    #[derive(Reflect, Default)]
+    pub enum Order { #[default] A, B }
+
+    #[derive(Reflect, Default)]
+    pub enum Fields { A(u8), #[default] B }
+
+    #[derive(Reflect, Default)]
+    pub enum Named { A { x: u8 }, #[default] B }
+}
+
+mod after {
+    use ggnet::*;
+
+    #[derive(Reflect, Default)]
+    pub struct Struct { pub ab: u8, pub c: u8 }
+
+    #[derive(Reflect, Default)]
+    pub enum Split { #[default] AB }
+
+    #[derive(Reflect, Default)]
+    pub enum Order { #[default] B, A }
+
+    #[derive(Reflect, Default)]
+    pub enum Fields { #[default] A, B(u8) }
+
+    #[derive(Reflect, Default)]
+    pub enum Named { A { y: u8 }, #[default] B }
+}
+
+mod same {
+    use ggnet::*;
+
+    #[derive(Reflect, Default)]
+    pub struct Struct { pub a: u8, pub bc: u8 }
+}
+
+#[test]
+fn same_layout_same_fingerprint() {
+    assert_eq!(before::Struct::fingerprint(), same::Struct::fingerprint());
+}
+
+#[test]
+fn renamed_fields_differ() {
+    assert_ne!(before::Struct::fingerprint(), after::Struct::fingerprint());
+    assert_ne!(before::Named::fingerprint(), after::Named::fingerprint());
+}
+
+#[test]
+fn split_or_moved_variants_differ() {
+    assert_ne!(before::Split::fingerprint(), after::Split::fingerprint());
+    assert_ne!(before::Order::fingerprint(), after::Order::fingerprint());
+    assert_ne!(before::Fields::fingerprint(), after::Fields::fingerprint());
+}
@@ -0,0 +1,291 @@
+#[macro_use] extern crate ggnet_derive;
+extern crate ggnet;
+
+use ggnet::*;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Reflect, Default)]
+pub struct Room<T: Tag> {
+    pub title: String,
+    pub chat: Node<Chat, T>,
+}
+
+#[derive(Reflect, Default)]
+pub struct Chat {
+    pub messages: Vec<String>,
+}
+
+rpc! {
+    rpcs<T: Tag> RoomRPC for Room<T> {
+        rpc rename(x: Node, title: String) {
+            x.as_mut().title = title;
+            x.member_modified("title".into());
+        }
+    }
+    rpcs<> ChatRPC for Chat {
+        rpc say(x: Node, message: String) {
+            x.member_vec_push("messages".into(), message);
+        }
+    }
+}
+
+/// A client `Config` that gives up instead of blocking forever when something is wrong.
+fn config() -> Config {
+    Config {
+        timeout: Some(Duration::from_secs(5)),
+        ..Config::default()
+    }
+}
+
+#[test]
+fn loopback_round_trip() {
+    let mut server = Server::new();
+    let chat = server.make_node(Chat::default());
+    let remote = server.add_loopback_client(Room { title: "lobby".into(), chat: chat.clone() });
+
+    let mut client = Client::<Room<TagClient>>::with_config(remote, config()).unwrap();
+    assert_eq!(client.as_ref().title, "lobby");
+
+    client.as_mut().chat.say("hello".into());
+    server.update();
+    assert_eq!(chat.as_ref().messages, vec!["hello".to_string()]);
+
+    client.update();
+    assert_eq!(client.as_ref().chat.as_ref().messages, vec!["hello".to_string()]);
+
+    let events = server.events();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], ServerEvent::ClientConnected(1)));
+}
+
+/// Updates the `Server` until it has handled everything the loopback clients sent, 
+///  a single update handles one message per client.
+fn update(server: &mut Server) {
+    for _ in 0..10 {
+        server.update();
+    }
+}
+
+/// Calls `rename` on `node` over `conn`, without checking if the node belongs to the client.
+fn rename(conn: &Connection, node: u32, title: &str) {
+    let mut ser = Serializer::new(Vec::new());
+    String::from("rename").reflect(&mut ser).unwrap();
+    String::from(title).reflect(&mut ser).unwrap();
+    conn.send(node, &ser.writer);
+}
+
+#[test]
+fn rpcs_on_nodes_that_were_not_replicated_are_denied() {
+    let mut server = Server::new();
+    let chat = server.make_node(Chat::default());
+    let a_conn = server.add_loopback_client(Room { title: "a".into(), chat: chat.clone() });
+    let b = server.add_loopback_client(Room { title: "b".into(), chat: chat.clone() });
+    let mut a = Client::<Room<TagClient>>::with_config(a_conn.clone(), config()).unwrap();
+    let b = Client::<Room<TagClient>>::with_config(b, config()).unwrap();
+    server.events();
+
+    // the root of b was never replicated to a, the shared chat was
+    rename(&a_conn, b.id(), "hacked");
+    a.as_mut().chat.say("hello".into());
+    update(&mut server);
+
+    assert_eq!(server.client_root::<Room<TagServer>>(2).unwrap().as_ref().title, "b");
+    assert_eq!(chat.as_ref().messages, vec!["hello".to_string()]);
+
+    let events = server.events();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], ServerEvent::RpcDenied(1, id, ref rpc) if id == b.id() && rpc == "rename"));
+}
+
+#[test]
+fn rpc_filter_can_refuse_rpcs() {
+    let mut server = Server::new();
+    let chat = server.make_node(Chat::default());
+    let remote = server.add_loopback_client(Room { title: "muted".into(), chat: chat.clone() });
+    let mut client = Client::<Room<TagClient>>::with_config(remote, config()).unwrap();
+    server.events();
+
+    server.set_rpc_filter(|caller, _, rpc| {
+        rpc != "say" || caller.root::<Room<TagServer>>().unwrap().as_ref().title != "muted"
+    });
+
+    client.as_mut().chat.say("hello".into());
+    client.rename("renamed".into());
+    update(&mut server);
+
+    assert!(chat.as_ref().messages.is_empty());
+    assert_eq!(server.client_root::<Room<TagServer>>(1).unwrap().as_ref().title, "renamed");
+
+    let events = server.events();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], ServerEvent::RpcDenied(1, _, ref rpc) if rpc == "say"));
+}
+
+#[test]
+fn resume_keeps_the_root_node() {
+    let mut server = Server::new();
+    server.set_session_grace(Some(Duration::from_secs(10)));
+    let chat = server.make_node(Chat::default());
+
+    let (transports, received) = channel();
+    let (tokens, token) = channel();
+    let (done, finished) = channel();
+
+    // the client runs on it's own thread, since the `Server` has to be updated while it connects
+    let client = thread::spawn(move || {
+        let conn = Connection::from_transport(received.recv().unwrap(), 0);
+        let mut client = Client::<Room<TagClient>>::with_config(conn, config()).unwrap();
+        client.rename("renamed".into());
+
+        let start = Instant::now();
+        while client.as_ref().title != "renamed" {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            client.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+        tokens.send(client.session()).unwrap();
+
+        // dropping the connection without closing it looks like a network failure
+        let session = client.session();
+        drop(client);
+
+        let conn = Connection::from_transport(received.recv().unwrap(), 0);
+        conn.configure(config());
+        let client = Client::<Room<TagClient>>::resume(conn, session).unwrap();
+        assert_eq!(client.as_ref().title, "renamed");
+        assert_eq!(client.session(), session);
+
+        // keep the connection open until the `Server` has been checked
+        tokens.send(session).unwrap();
+        finished.recv().unwrap();
+    });
+
+    let (ours, theirs) = ChannelTransport::pair();
+    server.add_transport_client(ours, Room { title: "lobby".into(), chat: chat.clone() });
+    transports.send(theirs).unwrap();
+
+    while token.try_recv().is_err() {
+        server.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+    while !server.clients().is_empty() {
+        server.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let (ours, theirs) = ChannelTransport::pair();
+    server.add_transport_client(ours, Room { title: "lobby".into(), chat: chat.clone() });
+    transports.send(theirs).unwrap();
+
+    while token.try_recv().is_err() {
+        assert!(!client.is_finished());
+        server.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let events = server.events();
+    assert_eq!(events.len(), 3);
+    assert!(matches!(events[0], ServerEvent::ClientConnected(1)));
+    assert!(matches!(events[1], ServerEvent::ClientDisconnected(1, _)));
+    assert!(matches!(events[2], ServerEvent::ClientResumed(2, 1)));
+    assert_eq!(server.clients(), vec![2]);
+
+    done.send(()).unwrap();
+    client.join().unwrap();
+}
+
+#[test]
+fn polled_round_trip() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+
+    // the accepted stream is blocking, `add_polled_client` has to change that or `update` hangs
+    let mut server = Server::new();
+    let chat = server.make_node(Chat::default());
+    server.add_polled_client(accepted.try_clone().unwrap(), accepted, Room { title: "lobby".into(), chat: chat.clone() }).unwrap();
+
+    let conn = Connection::polled(stream.try_clone().unwrap(), stream, 0).unwrap();
+    let mut client = Client::<Room<TagClient>>::with_config(conn, config()).unwrap();
+    assert_eq!(client.as_ref().title, "lobby");
+
+    client.as_mut().chat.say("hello".into());
+    let start = Instant::now();
+    while client.as_ref().chat.as_ref().messages.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        server.update();
+        client.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(chat.as_ref().messages, vec!["hello".to_string()]);
+}
+
+#[test]
+fn rpcs_are_decoded_with_the_limits_of_the_client() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+
+    let mut server = Server::new();
+    let chat = server.make_node(Chat::default());
+    let mut strict = Config::default();
+    strict.limits.max_string_len = 8;
+    server.add_client_with_config(accepted.try_clone().unwrap(), accepted, Room { title: "lobby".into(), chat }, strict);
+    server.events();
+
+    let conn = Connection::new(stream.try_clone().unwrap(), stream, 0);
+    let mut client = Client::<Room<TagClient>>::with_config(conn, config()).unwrap();
+    client.rename("longer than eight bytes".into());
+
+    let start = Instant::now();
+    let events = loop {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        server.update();
+        let events = server.events();
+        if !events.is_empty() {
+            break events;
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+    assert!(matches!(events[0], ServerEvent::RpcFailed(1, Error::LimitExceeded("string length", _))));
+}
+
+#[test]
+fn websocket_round_trip() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (done, finished) = channel();
+
+    let client = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let (w, r) = WebSocket::connect(stream.try_clone().unwrap(), stream, "localhost", "/").unwrap();
+        let mut client = Client::<Room<TagClient>>::with_config(Connection::new(w, r, 0), config()).unwrap();
+        assert_eq!(client.as_ref().title, "lobby");
+
+        client.as_mut().chat.say("hello".into());
+        let start = Instant::now();
+        while client.as_ref().chat.as_ref().messages.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            client.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+        done.send(()).unwrap();
+    });
+
+    let mut server = Server::new();
+    let chat = server.make_node(Chat::default());
+    let (stream, _) = listener.accept().unwrap();
+    let (w, r) = WebSocket::accept(stream.try_clone().unwrap(), stream).unwrap();
+    server.add_client(w, r, Room { title: "lobby".into(), chat: chat.clone() });
+
+    while finished.try_recv().is_err() {
+        assert!(!client.is_finished());
+        server.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(chat.as_ref().messages, vec!["hello".to_string()]);
+    client.join().unwrap();
+}
@@ -0,0 +1,72 @@
+#[macro_use] extern crate ggnet_derive;
+extern crate ggnet;
+
+use ggnet::*;
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Reflect, Default)]
+pub struct Player {
+    pub name: String,
+    pub x: i32,
+}
+
+rpc! {
+    rpcs<> PlayerRPC for Player { }
+}
+
+/// Two connected UDP sockets on the loopback interface.
+fn sockets() -> (UdpSocket, UdpSocket) {
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    a.connect(b.local_addr().unwrap()).unwrap();
+    b.connect(a.local_addr().unwrap()).unwrap();
+    (a, b)
+}
+
+/// Waits for a datagram on a non-blocking socket.
+fn recv(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = [0u8; 65536];
+    let start = Instant::now();
+    loop {
+        match socket.recv(&mut buf) {
+            Ok(n) => return buf[..n].to_vec(),
+            Err(_) => {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(1));
+            },
+        }
+    }
+}
+
+#[test]
+fn late_member_updates_leave_other_members_alone() {
+    let (server_socket, client_socket) = sockets();
+    let replay = server_socket.try_clone().unwrap();
+    let sniff = client_socket.try_clone().unwrap();
+    let (ours, theirs) = ChannelTransport::pair();
+
+    let mut server = Server::new();
+    server.add_transport_client(UdpTransport::new(ours, server_socket).unwrap(), Player::default());
+    let conn = Connection::from_transport(UdpTransport::new(theirs, client_socket).unwrap(), 0);
+    let mut client = Client::<Player>::new(conn).unwrap();
+    let mut player = server.client_root::<Player>(1).unwrap();
+
+    // the datagram is taken before the client sees it, and delivered after a reliable update
+    player.as_mut().x = 5;
+    player.member_modified_unreliable("x".into());
+    let datagram = recv(&sniff);
+
+    player.as_mut().name = "renamed".into();
+    player.member_modified("name".into());
+    replay.send(&datagram).unwrap();
+
+    let start = Instant::now();
+    while client.as_ref().x != 5 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        client.update();
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(client.as_ref().name, "renamed");
+}